@@ -0,0 +1,22 @@
+//! Passes the git revision of the wasmer dependency to `cache.rs`
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let manifest = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.toml");
+    println!("cargo:rerun-if-changed={}", manifest.display());
+    let toml = fs::read_to_string(&manifest).unwrap_or_default();
+    println!("cargo:rustc-env=TESSERACTWASMER_WASMER_REV={}", wasmer_rev(&toml).unwrap_or("unknown"));
+}
+
+/// `rev` of the `[dependencies.wasmer]` table
+fn wasmer_rev(toml: &str) -> Option<&str> {
+    let table = &toml[toml.find("[dependencies.wasmer]")? + "[dependencies.wasmer]".len()..];
+    let table = &table[..table.find("\n[").unwrap_or(table.len())];
+    table.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == "rev")
+        .map(|(_, value)| value.trim().trim_matches('"'))
+}
@@ -0,0 +1,200 @@
+//! On-disk cache of the compiled module
+//!
+//! Compiling `tesseract-core.wasm` takes seconds, loading a serialized
+//! artifact milliseconds. Artifacts are keyed by everything that makes them
+//! incompatible: the wasm itself and its import manifest, the exact wasmer
+//! revision, the compiler with its middlewares, and the target and CPU
+//! features of the engine.
+//! `Module::deserialize` trusts its input completely, so every
+//! artifact carries a checksum that is verified first; anything that
+//! doesn't match is recompiled and overwritten.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use sha2::{Digest, Sha256};
+use wasmer::{Engine, Module, Store};
+use crate::error::OcrError;
+use crate::imports::ImportManifest;
+
+/// git revision of the wasmer dependency, `wasmer::VERSION` stays the same
+/// across the commits of a release
+const WASMER_REV: &str = env!("TESSERACTWASMER_WASMER_REV");
+
+/// Tells apart the temporary files of concurrent stores in this process
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Start of every artifact file, bump the version when the layout changes
+const MAGIC: &[u8; 8] = b"TWMCACH1";
+
+/// Length of the header: magic, payload length, payload checksum
+const HEADER_SIZE: usize = 8 + 8 + 32;
+
+/// A directory of compiled modules, safe to share between processes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleCache {
+    pub dir: PathBuf,
+}
+
+impl ModuleCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Loads the artifact for `wasm` or compiles it and stores the result;
+    /// `manifest` has to be the one the engine of `store` was created for
+    ///
+    /// Failing to write the cache is not an error, the module is still usable.
+    pub fn load_or_compile(&self, store: &Store, wasm: &[u8], manifest: &ImportManifest) -> Result<Module, OcrError> {
+        let path = self.dir.join(format!("{}.bin", cache_key(wasm, manifest, store.engine())));
+        if let Some(module) = self.load(store, &path) {
+            return Ok(module);
+        }
+        let module = Module::from_binary(store, wasm).map_err(OcrError::Compile)?;
+        let artifact = module.serialize().map_err(OcrError::Serialize)?;
+        let _ = self.store(&path, &artifact);
+        Ok(module)
+    }
+
+    fn load(&self, store: &Store, path: &Path) -> Option<Module> {
+        let file = fs::read(path).ok()?;
+        let payload = verify(&file)?;
+        // the checksum rules out truncated or corrupted files, the key
+        // artifacts of other wasmer revisions, compilers and hosts
+        unsafe { Module::deserialize(store, payload) }.ok()
+    }
+
+    fn store(&self, path: &Path, artifact: &[u8]) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // write under a unique name and rename, so that concurrent
+        // processes and threads never see a partial file
+        let counter = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp = path.with_extension(format!("{}.{counter}.tmp", std::process::id()));
+        let result = (|| {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(MAGIC)?;
+            file.write_all(&(artifact.len() as u64).to_le_bytes())?;
+            file.write_all(&Sha256::digest(artifact))?;
+            file.write_all(artifact)?;
+            file.sync_all()?;
+            fs::rename(&tmp, path)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+}
+
+/// The payload of an artifact file if its header and checksum match
+fn verify(file: &[u8]) -> Option<&[u8]> {
+    if file.len() < HEADER_SIZE || &file[..8] != MAGIC {
+        return None;
+    }
+    let len = u64::from_le_bytes(file[8..16].try_into().ok()?);
+    let payload = &file[HEADER_SIZE..];
+    if payload.len() as u64 != len || Sha256::digest(payload)[..] != file[16..HEADER_SIZE] {
+        return None;
+    }
+    Some(payload)
+}
+
+/// File name of the artifact: hash of the wasm, its manifest (the
+/// preemption middleware calls an import by its minified name), the wasmer
+/// version and revision, the compiler and the target and CPU features of
+/// `engine`
+fn cache_key(wasm: &[u8], manifest: &ImportManifest, engine: &Engine) -> String {
+    let target = engine.target();
+    let manifest = serde_json::to_vec(manifest).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    for part in [
+        wasm,
+        &manifest,
+        wasmer::VERSION.as_bytes(),
+        WASMER_REV.as_bytes(),
+        crate::COMPILER_ID.as_bytes(),
+        target.triple().to_string().as_bytes(),
+        format!("{:?}", target.cpu_features()).as_bytes(),
+    ] {
+        // length-prefixed, so that no two keys hash the same bytes
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The smallest valid module, no sections
+    const EMPTY_WASM: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn test_cache(name: &str) -> ModuleCache {
+        let dir = std::env::temp_dir().join(format!("tesseractwasmer-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        ModuleCache::new(dir)
+    }
+
+    fn stored(cache: &ModuleCache, artifact: &[u8]) -> Vec<u8> {
+        let path = cache.dir.join("artifact.bin");
+        cache.store(&path, artifact).unwrap();
+        fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn verifies_artifact_files() {
+        let cache = test_cache("verify");
+        let file = stored(&cache, b"artifact");
+        assert_eq!(verify(&file), Some(&b"artifact"[..]));
+        assert_eq!(verify(&stored(&cache, b"")), Some(&b""[..]));
+
+        assert_eq!(verify(&file[..file.len() - 1]), None);
+        assert_eq!(verify(&file[..HEADER_SIZE - 1]), None);
+        assert_eq!(verify(&[file.as_slice(), b"!"].concat()), None);
+        let mut corrupted = file.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(verify(&corrupted), None);
+        let mut bad_magic = file.clone();
+        bad_magic[7] = b'0';
+        assert_eq!(verify(&bad_magic), None);
+
+        // only the renamed file is left behind
+        assert_eq!(fs::read_dir(&cache.dir).unwrap().count(), 1);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn cache_key_covers_wasm_and_manifest() {
+        let manifest = ImportManifest::default();
+        let engine = crate::new_engine(&manifest);
+        let key = cache_key(EMPTY_WASM, &manifest, &engine);
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, cache_key(EMPTY_WASM, &manifest, &crate::new_engine(&manifest)));
+        assert_ne!(key, cache_key(b"\0asm\x01\0\0\0\0", &manifest, &engine));
+        let mut renamed = manifest.clone();
+        renamed.imports.insert("a".to_string(), crate::interrupt::PREEMPTION_IMPORT.to_string());
+        assert_ne!(key, cache_key(EMPTY_WASM, &renamed, &engine));
+    }
+
+    #[test]
+    fn recompiles_broken_artifacts() {
+        let cache = test_cache("recompile");
+        let manifest = ImportManifest::default();
+        let store = Store::new_with_engine(&crate::new_engine(&manifest));
+        cache.load_or_compile(&store, EMPTY_WASM, &manifest).unwrap();
+        let path = cache.dir.join(format!("{}.bin", cache_key(EMPTY_WASM, &manifest, store.engine())));
+        let file = fs::read(&path).unwrap();
+        assert!(verify(&file).is_some());
+        assert!(cache.load(&store, &path).is_some());
+
+        for broken in [&file[..file.len() / 2], &file[HEADER_SIZE..]] {
+            fs::write(&path, broken).unwrap();
+            assert!(cache.load(&store, &path).is_none());
+            cache.load_or_compile(&store, EMPTY_WASM, &manifest).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), file);
+        }
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
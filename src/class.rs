@@ -0,0 +1,391 @@
+//! Host side of embind `class_<T>` bindings
+//!
+//! `__embind_register_class`, `__embind_register_class_constructor` and
+//! `__embind_register_class_function` describe a C++ class as a set of
+//! invoker functions living in the indirect function table. Instead of
+//! crafting JS functions like `craftInvokerFunction` does, we record the
+//! table indices and argument types so that `invoke::call` can drive them.
+
+use std::collections::BTreeMap;
+use wasmer::{AsStoreMut, FunctionEnv, FunctionEnvMut, MemorySize, RuntimeError, WasmPtr};
+use crate::embind::{EmValue, RawType, RegisteredType, TypeKind};
+use crate::env::{EmscriptenEnv, read_latin1_string, read_u32, trap};
+use crate::invoke;
+
+/// A function pointer handed over by `embind__requireFunction`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionPtr {
+    /// emscripten signature string, e.g. `"iii"`
+    pub signature: String,
+    /// index into the indirect function table
+    pub index: u32,
+}
+
+/// A constructor or member function, ready to be called through `invoke::call`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invoker {
+    pub human_name: String,
+    pub invoker: FunctionPtr,
+    /// raw constructor or pointer to the member function pointer,
+    /// always passed as the first argument of the invoker
+    pub context: u32,
+    /// return type, then `this` (methods only), then the parameter types
+    pub arg_types: Vec<RawType>,
+    pub is_method: bool,
+}
+
+impl Invoker {
+    /// Number of arguments the caller has to supply (without `this`)
+    pub fn arity(&self) -> usize {
+        self.arg_types.len().saturating_sub(if self.is_method { 2 } else { 1 })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegisteredClass {
+    pub name: String,
+    pub raw_type: RawType,
+    pub pointer_type: RawType,
+    pub const_pointer_type: RawType,
+    pub base_class: Option<RawType>,
+    pub get_actual_type: FunctionPtr,
+    pub upcast: Option<FunctionPtr>,
+    pub downcast: Option<FunctionPtr>,
+    pub destructor: FunctionPtr,
+    /// constructors by number of parameters
+    pub constructors: BTreeMap<usize, Invoker>,
+    /// member functions by name, overloads by number of parameters
+    pub methods: BTreeMap<String, BTreeMap<usize, Invoker>>,
+    pub pure_virtual_functions: Vec<String>,
+}
+
+impl RegisteredClass {
+    pub fn constructor(&self, arity: usize) -> Result<&Invoker, String> {
+        if self.constructors.is_empty() {
+            return Err(format!("{} has no accessible constructor", self.name));
+        }
+        self.constructors.get(&arity).ok_or_else(|| {
+            format!(
+                "Tried to invoke ctor of {} with invalid number of parameters ({arity}) - expected ({:?}) parameters instead!",
+                self.name, self.constructors.keys().collect::<Vec<_>>(),
+            )
+        })
+    }
+
+    pub fn method(&self, name: &str, arity: usize) -> Result<&Invoker, String> {
+        let overloads = self.methods.get(name)
+            .ok_or_else(|| format!("{} has no method {name}", self.name))?;
+        overloads.get(&arity).ok_or_else(|| {
+            format!(
+                "Function '{}.{name}' called with an invalid number of arguments ({arity}) - expects one of ({:?})!",
+                self.name, overloads.keys().collect::<Vec<_>>(),
+            )
+        })
+    }
+}
+
+/// All classes registered so far, keyed by the `typeid` of the class
+#[derive(Debug, Default, Clone)]
+pub struct ClassRegistry {
+    classes: BTreeMap<RawType, RegisteredClass>,
+}
+
+impl ClassRegistry {
+    pub fn get(&self, raw: RawType) -> Option<&RegisteredClass> {
+        self.classes.get(&raw)
+    }
+
+    pub fn by_name(&self, name: &str) -> Result<&RegisteredClass, String> {
+        self.classes.values()
+            .find(|c| c.name == name)
+            .ok_or_else(|| format!("class {name} is not registered"))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RegisteredClass> {
+        self.classes.values()
+    }
+
+    fn require_mut(&mut self, raw: RawType, human_name: &str) -> Result<&mut RegisteredClass, String> {
+        self.classes.get_mut(&raw).ok_or_else(|| format!("{human_name}: class {raw:#x} is not registered"))
+    }
+}
+
+/// Constructs a new `class_name` on the guest heap, returns the raw pointer
+pub fn construct(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    class_name: &str,
+    args: &[EmValue],
+) -> Result<u32, String> {
+    let ctor = env.as_ref(&*store).classes.by_name(class_name)?.constructor(args.len())?.clone();
+    match invoke::call(store, env, &ctor, None, args)? {
+        EmValue::Pointer { ptr, .. } => Ok(ptr),
+        other => Err(format!("{}: expected a pointer, got {other:?}", ctor.human_name)),
+    }
+}
+
+/// Calls `class_name::method` on the object at `this`
+pub fn call_method(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    class_name: &str,
+    this: u32,
+    method: &str,
+    args: &[EmValue],
+) -> Result<EmValue, String> {
+    let invoker = env.as_ref(&*store).classes.by_name(class_name)?.method(method, args.len())?.clone();
+    invoke::call(store, env, &invoker, Some(this), args)
+}
+
+/// Runs the registered destructor (`delete`) on the object at `this`
+pub fn delete(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    class_name: &str,
+    this: u32,
+) -> Result<(), String> {
+    let destructor = env.as_ref(&*store).classes.by_name(class_name)?.destructor.clone();
+    invoke::call_raw(store, env, &destructor, &[this])?;
+    Ok(())
+}
+
+pub fn read_function_ptr<M: MemorySize>(
+    ctx: &FunctionEnvMut<'_, EmscriptenEnv>,
+    signature: WasmPtr<u8, M>,
+    index: u32,
+) -> Result<FunctionPtr, RuntimeError> {
+    let view = ctx.data().memory_view(ctx);
+    let signature = read_latin1_string(&view, signature.offset().into()).map_err(trap)?;
+    Ok(FunctionPtr { signature, index })
+}
+
+/// `heap32VectorToArray`
+fn read_raw_types<M: MemorySize>(
+    ctx: &FunctionEnvMut<'_, EmscriptenEnv>,
+    count: u32,
+    first: WasmPtr<u8, M>,
+) -> Result<Vec<RawType>, RuntimeError> {
+    let view = ctx.data().memory_view(ctx);
+    let first: u64 = first.offset().into();
+    (0..count as u64)
+        .map(|i| read_u32(&view, first + i * 4).map_err(trap))
+        .collect()
+}
+
+// ----------
+//
+// __embind_register_class(rawType, rawPointerType, rawConstPointerType,
+//     baseClassRawType, getActualTypeSignature, getActualType,
+//     upcastSignature, upcast, downcastSignature, downcast, name,
+//     destructorSignature, rawDestructor)
+#[allow(clippy::too_many_arguments)]
+pub fn __embind_register_class<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    raw_pointer_type: u32,
+    raw_const_pointer_type: u32,
+    base_class_raw_type: u32,
+    get_actual_type_signature: WasmPtr<u8, M>,
+    get_actual_type: u32,
+    upcast_signature: WasmPtr<u8, M>,
+    upcast: u32,
+    downcast_signature: WasmPtr<u8, M>,
+    downcast: u32,
+    name: WasmPtr<u8, M>,
+    destructor_signature: WasmPtr<u8, M>,
+    raw_destructor: u32,
+) -> Result<(), RuntimeError> {
+    let name = {
+        let view = ctx.data().memory_view(&ctx);
+        read_latin1_string(&view, name.offset().into()).map_err(trap)?
+    };
+    let class = RegisteredClass {
+        raw_type,
+        pointer_type: raw_pointer_type,
+        const_pointer_type: raw_const_pointer_type,
+        base_class: Some(base_class_raw_type).filter(|b| *b != 0),
+        get_actual_type: read_function_ptr(&ctx, get_actual_type_signature, get_actual_type)?,
+        upcast: match upcast {
+            0 => None,
+            p => Some(read_function_ptr(&ctx, upcast_signature, p)?),
+        },
+        downcast: match downcast {
+            0 => None,
+            p => Some(read_function_ptr(&ctx, downcast_signature, p)?),
+        },
+        destructor: read_function_ptr(&ctx, destructor_signature, raw_destructor)?,
+        constructors: BTreeMap::new(),
+        methods: BTreeMap::new(),
+        pure_virtual_functions: Vec::new(),
+        name: name.clone(),
+    };
+
+    let env = ctx.data_mut();
+    if env.classes.classes.contains_key(&raw_type) {
+        return Err(trap(format!("Cannot register public name '{name}' twice")));
+    }
+    let pointer_kinds = [
+        (raw_type, name.clone(), true, false),
+        (raw_pointer_type, format!("{name}*"), false, false),
+        (raw_const_pointer_type, format!("{name} const*"), false, true),
+    ];
+    for (raw, ptr_name, is_reference, is_const) in pointer_kinds {
+        env.registry.register(raw, RegisteredType {
+            name: ptr_name,
+            kind: TypeKind::ClassPointer { class: raw_type, is_reference, is_const },
+        }).map_err(trap)?;
+    }
+    env.classes.classes.insert(raw_type, class);
+    Ok(())
+}
+
+// ----------
+//
+// __embind_register_class_constructor(rawClassType, argCount, rawArgTypesAddr,
+//     invokerSignature, invoker, rawConstructor)
+pub fn __embind_register_class_constructor<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_class_type: u32,
+    arg_count: u32,
+    raw_arg_types_addr: WasmPtr<u8, M>,
+    invoker_signature: WasmPtr<u8, M>,
+    invoker: u32,
+    raw_constructor: u32,
+) -> Result<(), RuntimeError> {
+    if arg_count == 0 {
+        return Err(trap("constructor without return type"));
+    }
+    let arg_types = read_raw_types(&ctx, arg_count, raw_arg_types_addr)?;
+    let invoker = read_function_ptr(&ctx, invoker_signature, invoker)?;
+    let class = ctx.data_mut().classes.require_mut(raw_class_type, "constructor").map_err(trap)?;
+    let arity = arg_count as usize - 1;
+    if class.constructors.contains_key(&arity) {
+        return Err(trap(format!(
+            "Cannot register multiple constructors with identical number of parameters ({arity}) for class '{}'!",
+            class.name,
+        )));
+    }
+    let human_name = format!("constructor {}", class.name);
+    class.constructors.insert(arity, Invoker {
+        human_name,
+        invoker,
+        context: raw_constructor,
+        arg_types,
+        is_method: false,
+    });
+    Ok(())
+}
+
+// ----------
+//
+// __embind_register_class_function(rawClassType, methodName, argCount,
+//     rawArgTypesAddr, invokerSignature, rawInvoker, context, isPureVirtual)
+#[allow(clippy::too_many_arguments)]
+pub fn __embind_register_class_function<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_class_type: u32,
+    method_name: WasmPtr<u8, M>,
+    arg_count: u32,
+    raw_arg_types_addr: WasmPtr<u8, M>,
+    invoker_signature: WasmPtr<u8, M>,
+    raw_invoker: u32,
+    context: u32,
+    is_pure_virtual: u32,
+) -> Result<(), RuntimeError> {
+    if arg_count < 2 {
+        return Err(trap("argTypes array size mismatch! Must at least get return value and 'this' types!"));
+    }
+    let method_name = {
+        let view = ctx.data().memory_view(&ctx);
+        read_latin1_string(&view, method_name.offset().into()).map_err(trap)?
+    };
+    let arg_types = read_raw_types(&ctx, arg_count, raw_arg_types_addr)?;
+    let invoker = read_function_ptr(&ctx, invoker_signature, raw_invoker)?;
+    let class = ctx.data_mut().classes.require_mut(raw_class_type, &method_name).map_err(trap)?;
+    if is_pure_virtual != 0 {
+        class.pure_virtual_functions.push(method_name.clone());
+    }
+    let human_name = format!("{}.{method_name}", class.name);
+    class.methods
+        .entry(method_name)
+        .or_default()
+        .insert(arg_count as usize - 2, Invoker {
+            human_name,
+            invoker,
+            context,
+            arg_types,
+            is_method: true,
+        });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{Function, Memory32};
+    use crate::env::testing::{call, env_with_memory, write_c_string};
+    use crate::env::write_u32;
+    use super::*;
+
+    const CLASS: i32 = 100;
+    const POINTER: i32 = 104;
+    const CONST_POINTER: i32 = 108;
+    // guest memory of the registration arguments
+    const NAME: u64 = 64;
+    const SIGNATURE: u64 = 128;
+    const METHOD_NAME: u64 = 192;
+    const ARG_TYPES: u64 = 256;
+
+    #[test]
+    fn registers_classes() {
+        let (mut store, env) = env_with_memory();
+        write_c_string(&store, &env, NAME, "OCREngine");
+        write_c_string(&store, &env, SIGNATURE, "ii");
+        write_c_string(&store, &env, METHOD_NAME, "getText");
+        {
+            let view = env.as_ref(&store).memory_view(&store);
+            write_u32(&view, ARG_TYPES, POINTER as u32).unwrap();
+            write_u32(&view, ARG_TYPES + 4, POINTER as u32).unwrap();
+            write_u32(&view, ARG_TYPES + 8, POINTER as u32).unwrap();
+        }
+        let (name, signature, method_name, arg_types) = (NAME as i32, SIGNATURE as i32, METHOD_NAME as i32, ARG_TYPES as i32);
+
+        let register_class = Function::new_typed_with_env(&mut store, &env, __embind_register_class::<Memory32>);
+        let class_args = [CLASS, POINTER, CONST_POINTER, 0, signature, 1, signature, 0, signature, 0, name, signature, 2];
+        call(&mut store, &register_class, &class_args).unwrap();
+        let e = call(&mut store, &register_class, &class_args).unwrap_err();
+        assert!(e.contains("Cannot register public name 'OCREngine' twice"), "{e}");
+        {
+            let env = env.as_ref(&store);
+            let class = env.classes.by_name("OCREngine").unwrap();
+            assert_eq!((class.raw_type, class.base_class, class.upcast.clone()), (CLASS as u32, None, None));
+            assert_eq!(class.destructor, FunctionPtr { signature: "ii".to_string(), index: 2 });
+            let names = [CLASS, POINTER, CONST_POINTER].map(|raw| env.registry.get(raw as u32).unwrap().name.clone());
+            assert_eq!(names, ["OCREngine", "OCREngine*", "OCREngine const*"]);
+        }
+
+        let register_constructor = Function::new_typed_with_env(&mut store, &env, __embind_register_class_constructor::<Memory32>);
+        call(&mut store, &register_constructor, &[CLASS, 1, arg_types, signature, 3, 4]).unwrap();
+        let e = call(&mut store, &register_constructor, &[CLASS, 1, arg_types, signature, 3, 4]).unwrap_err();
+        assert!(e.contains("multiple constructors with identical number of parameters (0)"), "{e}");
+        assert!(call(&mut store, &register_constructor, &[CLASS, 0, arg_types, signature, 3, 4]).is_err());
+        assert!(call(&mut store, &register_constructor, &[CLASS + 1, 1, arg_types, signature, 3, 4]).is_err());
+
+        let register_function = Function::new_typed_with_env(&mut store, &env, __embind_register_class_function::<Memory32>);
+        call(&mut store, &register_function, &[CLASS, method_name, 2, arg_types, signature, 5, 6, 0]).unwrap();
+        call(&mut store, &register_function, &[CLASS, method_name, 3, arg_types, signature, 7, 8, 1]).unwrap();
+        assert!(call(&mut store, &register_function, &[CLASS, method_name, 1, arg_types, signature, 5, 6, 0]).is_err());
+
+        let class = env.as_ref(&store).classes.by_name("OCREngine").unwrap();
+        let constructor = class.constructor(0).unwrap();
+        assert_eq!((constructor.context, constructor.arity(), constructor.is_method), (4, 0, false));
+        assert_eq!(constructor.arg_types, [POINTER as u32]);
+        assert!(class.constructor(1).unwrap_err().contains("invalid number of parameters (1)"));
+        // overloads by number of arguments, without `this`
+        let get_text = class.method("getText", 0).unwrap();
+        assert_eq!((get_text.human_name.as_str(), get_text.invoker.index, get_text.context), ("OCREngine.getText", 5, 6));
+        assert_eq!(class.method("getText", 1).unwrap().arity(), 1);
+        assert!(class.method("getText", 2).is_err());
+        assert!(class.method("getBoxes", 0).is_err());
+        assert_eq!(class.pure_virtual_functions, ["getText"]);
+    }
+}
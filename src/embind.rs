@@ -0,0 +1,634 @@
+//! Host side of embind's type registration
+//!
+//! The static constructors of `tesseract-core.wasm` announce every C++ type
+//! that crosses the JS boundary through the `__embind_register_*` imports.
+//! The JS glue (see `worker2.js`) keeps these in `registeredTypes`, keyed by
+//! the address of the C++ `typeid`; `TypeRegistry` is the Rust equivalent
+//! and knows how to turn wire values of each type into `EmValue`s.
+
+use std::collections::BTreeMap;
+use wasmer::{FunctionEnvMut, MemorySize, MemoryView, RuntimeError, Value, WasmPtr};
+use crate::class::FunctionPtr;
+use crate::env::{EmscriptenEnv, read_bytes, read_latin1_string, read_u16, read_u32, read_u64, trap};
+use crate::value_object::ValueObjectField;
+
+/// Address of the C++ `typeid` of a registered type
+pub type RawType = u32;
+
+/// Element type of a `typed_memory_view`, in the order of the
+/// `typeMapping` table in the JS glue
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryViewType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl MemoryViewType {
+    pub fn from_index(index: u32) -> Option<Self> {
+        use self::MemoryViewType::*;
+        Some(match index {
+            0 => I8,
+            1 => U8,
+            2 => I16,
+            3 => U16,
+            4 => I32,
+            5 => U32,
+            6 => F32,
+            7 => F64,
+            _ => return None,
+        })
+    }
+
+    pub fn element_size(&self) -> u32 {
+        use self::MemoryViewType::*;
+        match self {
+            I8 | U8 => 1,
+            I16 | U16 => 2,
+            I32 | U32 | F32 => 4,
+            F64 => 8,
+        }
+    }
+}
+
+/// How values of a registered type are laid out on the wire
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeKind {
+    Void,
+    Bool { size: u32, true_value: u32, false_value: u32 },
+    Integer { size: u32, signed: bool, min: i32, max: u32 },
+    BigInt { size: u32, signed: bool, min: i64, max: u64 },
+    Float { size: u32 },
+    /// `std::string` is UTF-8, any other registered byte string is Latin-1
+    StdString { utf8: bool },
+    StdWString { char_size: u32 },
+    MemoryView { element: MemoryViewType },
+    Emval,
+    /// One of the three converters of a `class_<T>`: `T` (reference),
+    /// `T*` and `T const*`, all of them plain pointers on the wire
+    ClassPointer { class: RawType, is_reference: bool, is_const: bool },
+    /// `value_object<T>`: a pointer to a temporary `T` on the wire, built
+    /// and torn down through the registered constructor / destructor
+    ValueObject { constructor: FunctionPtr, destructor: FunctionPtr, fields: Vec<ValueObjectField> },
+    /// `enum_<T>`: an integer on the wire, `values` maps names to values
+    Enum { size: u32, signed: bool, values: BTreeMap<String, i32> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisteredType {
+    pub name: String,
+    pub kind: TypeKind,
+}
+
+/// A value that crossed the embind boundary, decoded into host terms
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmValue {
+    Undefined,
+    Null,
+    Bool(bool),
+    /// All integer and float types, like a JS number
+    Number(f64),
+    BigInt(i64),
+    String(String),
+    /// Binary data for `std::string` parameters (a `Uint8Array` in JS)
+    Bytes(Vec<u8>),
+    /// A typed view into guest memory, `len` is in elements
+    MemoryView { element: MemoryViewType, ptr: u32, len: u32 },
+    /// Raw emval handle, not yet resolved against the handle table
+    Handle(u32),
+    /// Instance of a registered class living on the guest heap
+    Pointer { class: RawType, ptr: u32 },
+    /// Decoded `value_object`, fields by name
+    Object(BTreeMap<String, EmValue>),
+}
+
+impl RegisteredType {
+    /// Bytes this type occupies in an argument pack (`argPackAdvance`)
+    pub fn arg_pack_advance(&self) -> u64 {
+        match self.kind {
+            TypeKind::Void => 0,
+            _ => 8,
+        }
+    }
+
+    /// `fromWireType`: decodes a value returned from (or passed by) the guest
+    ///
+    /// For string types the wire value is a pointer to a malloc'ed
+    /// `{ u32 length; char data[] }` block which the caller has to `free`.
+    pub fn from_wire(&self, view: &MemoryView, wire: &Value) -> Result<EmValue, String> {
+        let as_i32 = || match wire {
+            Value::I32(v) => Ok(*v),
+            other => Err(format!("{}: expected i32 on the wire, got {other:?}", self.name)),
+        };
+        let v = match &self.kind {
+            TypeKind::Void => EmValue::Undefined,
+            TypeKind::Bool { .. } => EmValue::Bool(as_i32()? != 0),
+            TypeKind::Integer { size, signed, .. } => {
+                let v = as_i32()?;
+                if *signed {
+                    EmValue::Number(v as f64)
+                } else {
+                    let bits = 32 - 8 * size;
+                    EmValue::Number((((v as u32) << bits) >> bits) as f64)
+                }
+            },
+            TypeKind::BigInt { .. } => match wire {
+                Value::I64(v) => EmValue::BigInt(*v),
+                other => return Err(format!("{}: expected i64 on the wire, got {other:?}", self.name)),
+            },
+            TypeKind::Float { .. } => match wire {
+                Value::F32(v) => EmValue::Number(*v as f64),
+                Value::F64(v) => EmValue::Number(*v),
+                other => return Err(format!("{}: expected float on the wire, got {other:?}", self.name)),
+            },
+            TypeKind::StdString { utf8 } => {
+                let ptr = as_i32()? as u32 as u64;
+                let len = read_u32(view, ptr).map_err(|e| format!("{}: {e}", self.name))?;
+                let bytes = read_bytes(view, ptr + 4, len as usize).map_err(|e| format!("{}: {e}", self.name))?;
+                if *utf8 {
+                    EmValue::String(String::from_utf8_lossy(&bytes).into_owned())
+                } else {
+                    EmValue::String(bytes.iter().map(|b| *b as char).collect())
+                }
+            },
+            TypeKind::StdWString { char_size } => {
+                let ptr = as_i32()? as u32 as u64;
+                let len = read_u32(view, ptr).map_err(|e| format!("{}: {e}", self.name))? as u64;
+                let err = |e| format!("{}: {e}", self.name);
+                let s = if *char_size == 2 {
+                    let units = (0..len)
+                        .map(|i| read_u16(view, ptr + 4 + i * 2))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(err)?;
+                    String::from_utf16_lossy(&units)
+                } else {
+                    (0..len)
+                        .map(|i| read_u32(view, ptr + 4 + i * 4))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(err)?
+                        .into_iter()
+                        .map(|c| char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect()
+                };
+                EmValue::String(s)
+            },
+            TypeKind::MemoryView { element } => {
+                self.read_memory_view(view, as_i32()? as u32 as u64, *element)?
+            },
+            TypeKind::Emval => EmValue::Handle(as_i32()? as u32),
+            TypeKind::ClassPointer { class, .. } => EmValue::Pointer { class: *class, ptr: as_i32()? as u32 },
+            TypeKind::Enum { values, .. } => {
+                let v = as_i32()?;
+                if !values.values().any(|x| *x == v) {
+                    return Err(format!("{}: unknown enum value {v}", self.name));
+                }
+                EmValue::Number(v as f64)
+            },
+            TypeKind::ValueObject { .. } => {
+                return Err(format!("{}: value objects are decoded by invoke::from_wire", self.name));
+            },
+        };
+        Ok(v)
+    }
+
+    /// `readValueFromPointer`: decodes a value stored at `ptr`,
+    /// used for argument packs such as the one of `_emval_call`
+    pub fn read_from_pointer(&self, view: &MemoryView, ptr: u64) -> Result<EmValue, String> {
+        let err = |e| format!("{}: {e}", self.name);
+        let v = match &self.kind {
+            TypeKind::Void => EmValue::Undefined,
+            TypeKind::Bool { size, .. } => {
+                let raw = read_bytes(view, ptr, *size as usize).map_err(err)?;
+                EmValue::Bool(raw.iter().any(|b| *b != 0))
+            },
+            TypeKind::Integer { size, signed, .. } => {
+                let raw = read_bytes(view, ptr, *size as usize).map_err(err)?;
+                let n = match (size, signed) {
+                    (1, true) => raw[0] as i8 as f64,
+                    (1, false) => raw[0] as f64,
+                    (2, true) => i16::from_le_bytes([raw[0], raw[1]]) as f64,
+                    (2, false) => u16::from_le_bytes([raw[0], raw[1]]) as f64,
+                    (4, true) => i32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as f64,
+                    (4, false) => u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as f64,
+                    _ => return Err(format!("Unknown integer type: {}", self.name)),
+                };
+                EmValue::Number(n)
+            },
+            TypeKind::Enum { size, signed, .. } => {
+                // `enumReadValueFromPointer`
+                let raw = read_bytes(view, ptr, *size as usize).map_err(err)?;
+                let v = match (size, signed) {
+                    (1, true) => raw[0] as i8 as i32,
+                    (1, false) => raw[0] as i32,
+                    (2, true) => i16::from_le_bytes([raw[0], raw[1]]) as i32,
+                    (2, false) => u16::from_le_bytes([raw[0], raw[1]]) as i32,
+                    (4, _) => i32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
+                    _ => return Err(format!("Unknown integer type: {}", self.name)),
+                };
+                self.from_wire(view, &Value::I32(v))?
+            },
+            TypeKind::BigInt { .. } => EmValue::BigInt(read_u64(view, ptr).map_err(err)? as i64),
+            TypeKind::Float { size: 4 } => {
+                EmValue::Number(f32::from_bits(read_u32(view, ptr).map_err(err)?) as f64)
+            },
+            TypeKind::Float { .. } => EmValue::Number(f64::from_bits(read_u64(view, ptr).map_err(err)?)),
+            TypeKind::MemoryView { element } => self.read_memory_view(view, ptr, *element)?,
+            TypeKind::StdString { .. }
+            | TypeKind::StdWString { .. }
+            | TypeKind::Emval
+            | TypeKind::ClassPointer { .. }
+            | TypeKind::ValueObject { .. } => {
+                let wire = Value::I32(read_u32(view, ptr).map_err(err)? as i32);
+                self.from_wire(view, &wire)?
+            },
+        };
+        Ok(v)
+    }
+
+    /// `toWireType` for the types that can be encoded without calling
+    /// back into the guest (i.e. everything except strings)
+    pub fn to_wire(&self, value: &EmValue) -> Result<Value, String> {
+        let mismatch = || format!("cannot pass {value:?} as {}", self.name);
+        let v = match (&self.kind, value) {
+            (TypeKind::Bool { true_value, false_value, .. }, EmValue::Bool(b)) => {
+                Value::I32(if *b { *true_value } else { *false_value } as i32)
+            },
+            (TypeKind::Integer { signed, .. }, EmValue::Number(n)) => {
+                if *signed { Value::I32(*n as i32) } else { Value::I32(*n as u32 as i32) }
+            },
+            (TypeKind::Integer { .. }, EmValue::Bool(b)) => Value::I32(*b as i32),
+            (TypeKind::Enum { values, .. }, EmValue::Number(n)) if values.values().any(|v| *v as f64 == *n) => {
+                Value::I32(*n as i32)
+            },
+            (TypeKind::BigInt { .. }, EmValue::BigInt(n)) => Value::I64(*n),
+            (TypeKind::Float { size: 4 }, EmValue::Number(n)) => Value::F32(*n as f32),
+            (TypeKind::Float { .. }, EmValue::Number(n)) => Value::F64(*n),
+            (TypeKind::Emval, EmValue::Handle(h)) => Value::I32(*h as i32),
+            // the constant handles of `emval_handle_array`
+            (TypeKind::Emval, EmValue::Undefined) => Value::I32(crate::emval::UNDEFINED as i32),
+            (TypeKind::Emval, EmValue::Null) => Value::I32(crate::emval::NULL as i32),
+            (TypeKind::Emval, EmValue::Bool(true)) => Value::I32(crate::emval::TRUE as i32),
+            (TypeKind::Emval, EmValue::Bool(false)) => Value::I32(crate::emval::FALSE as i32),
+            (TypeKind::ClassPointer { .. }, EmValue::Pointer { ptr, .. }) => Value::I32(*ptr as i32),
+            _ => return Err(mismatch()),
+        };
+        Ok(v)
+    }
+
+    fn read_memory_view(&self, view: &MemoryView, handle: u64, element: MemoryViewType) -> Result<EmValue, String> {
+        let len = read_u32(view, handle).map_err(|e| format!("{}: {e}", self.name))?;
+        let ptr = read_u32(view, handle + 4).map_err(|e| format!("{}: {e}", self.name))?;
+        Ok(EmValue::MemoryView { element, ptr, len })
+    }
+}
+
+/// All types registered so far, keyed by their `typeid` address
+#[derive(Debug, Default, Clone)]
+pub struct TypeRegistry {
+    types: BTreeMap<RawType, RegisteredType>,
+}
+
+impl TypeRegistry {
+    /// `registerType`: fails if `raw` is null or already taken
+    pub fn register(&mut self, raw: RawType, ty: RegisteredType) -> Result<(), String> {
+        if raw == 0 {
+            return Err(format!("type \"{}\" must have a positive integer typeid pointer", ty.name));
+        }
+        if self.types.contains_key(&raw) {
+            return Err(format!("Cannot register type '{}' twice", ty.name));
+        }
+        self.types.insert(raw, ty);
+        Ok(())
+    }
+
+    /// `registerType` with `ignoreDuplicateRegistrations` (memory views)
+    pub fn register_or_ignore(&mut self, raw: RawType, ty: RegisteredType) -> Result<(), String> {
+        if self.types.contains_key(&raw) {
+            return Ok(());
+        }
+        self.register(raw, ty)
+    }
+
+    pub fn get(&self, raw: RawType) -> Option<&RegisteredType> {
+        self.types.get(&raw)
+    }
+
+    /// `requireRegisteredType`
+    pub fn require(&self, raw: RawType, human_name: &str) -> Result<&RegisteredType, String> {
+        self.get(raw).ok_or_else(|| format!("{human_name} has unknown type {raw:#x}"))
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<(RawType, &RegisteredType)> {
+        self.types.iter().find(|(_, t)| t.name == name).map(|(k, t)| (*k, t))
+    }
+
+    /// Values of the enum registered as `name`, `None` if there is no such enum
+    pub fn enum_values(&self, name: &str) -> Option<&BTreeMap<String, i32>> {
+        match self.find_by_name(name) {
+            Some((_, RegisteredType { kind: TypeKind::Enum { values, .. }, .. })) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+}
+
+fn register<M: MemorySize>(
+    ctx: &mut FunctionEnvMut<'_, EmscriptenEnv>,
+    raw: u32,
+    name: WasmPtr<u8, M>,
+    ignore_duplicates: bool,
+    kind: impl FnOnce(&str) -> Result<TypeKind, String>,
+) -> Result<(), RuntimeError> {
+    let (env, store) = (ctx.data(), ctx.as_store_ref());
+    let name = read_latin1_string(&env.memory_view(&store), name.offset().into()).map_err(trap)?;
+    let ty = RegisteredType { kind: kind(&name).map_err(trap)?, name };
+    let registry = &mut ctx.data_mut().registry;
+    if ignore_duplicates {
+        registry.register_or_ignore(raw, ty).map_err(trap)
+    } else {
+        registry.register(raw, ty).map_err(trap)
+    }
+}
+
+fn check_size(name: &str, size: u32) -> Result<u32, String> {
+    match size {
+        1 | 2 | 4 | 8 => Ok(size),
+        _ => Err(format!("Unknown type size: {size} ({name})")),
+    }
+}
+
+// ----------
+//
+// __embind_register_void(rawType, name)
+pub fn __embind_register_void<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    name: WasmPtr<u8, M>,
+) -> Result<(), RuntimeError> {
+    register(&mut ctx, raw_type, name, false, |_| Ok(TypeKind::Void))
+}
+
+// ----------
+//
+// __embind_register_bool(rawType, name, size, trueValue, falseValue)
+pub fn __embind_register_bool<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    name: WasmPtr<u8, M>,
+    size: u32,
+    true_value: u32,
+    false_value: u32,
+) -> Result<(), RuntimeError> {
+    register(&mut ctx, raw_type, name, false, |name| {
+        match check_size(name, size)? {
+            8 => Err(format!("Unknown boolean type size: {name}")),
+            size => Ok(TypeKind::Bool { size, true_value, false_value }),
+        }
+    })
+}
+
+// ----------
+//
+// __embind_register_integer(primitiveType, name, size, minRange, maxRange)
+pub fn __embind_register_integer<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    primitive_type: u32,
+    name: WasmPtr<u8, M>,
+    size: u32,
+    min_range: i32,
+    max_range: u32,
+) -> Result<(), RuntimeError> {
+    register(&mut ctx, primitive_type, name, false, |name| {
+        match check_size(name, size)? {
+            8 => Err(format!("Unknown integer type: {name}")),
+            size => Ok(TypeKind::Integer { size, signed: min_range != 0, min: min_range, max: max_range }),
+        }
+    })
+}
+
+// ----------
+//
+// __embind_register_bigint(primitiveType, name, size, minRange, maxRange),
+// the two i64 ranges arrive legalized as (low, high) pairs
+#[allow(clippy::too_many_arguments)]
+pub fn __embind_register_bigint<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    primitive_type: u32,
+    name: WasmPtr<u8, M>,
+    size: u32,
+    min_low: u32,
+    min_high: u32,
+    max_low: u32,
+    max_high: u32,
+) -> Result<(), RuntimeError> {
+    let min = ((min_high as u64) << 32 | min_low as u64) as i64;
+    let max = (max_high as u64) << 32 | max_low as u64;
+    register(&mut ctx, primitive_type, name, false, |name| {
+        let size = check_size(name, size)?;
+        Ok(TypeKind::BigInt { size, signed: min != 0, min, max })
+    })
+}
+
+// ----------
+//
+// __embind_register_float(rawType, name, size)
+pub fn __embind_register_float<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    name: WasmPtr<u8, M>,
+    size: u32,
+) -> Result<(), RuntimeError> {
+    register(&mut ctx, raw_type, name, false, |name| {
+        match check_size(name, size)? {
+            size @ (4 | 8) => Ok(TypeKind::Float { size }),
+            _ => Err(format!("Unknown float type: {name}")),
+        }
+    })
+}
+
+// ----------
+//
+// __embind_register_std_string(rawType, name)
+pub fn __embind_register_std_string<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    name: WasmPtr<u8, M>,
+) -> Result<(), RuntimeError> {
+    register(&mut ctx, raw_type, name, false, |name| {
+        Ok(TypeKind::StdString { utf8: name == "std::string" })
+    })
+}
+
+// ----------
+//
+// __embind_register_std_wstring(rawType, charSize, name)
+pub fn __embind_register_std_wstring<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    char_size: u32,
+    name: WasmPtr<u8, M>,
+) -> Result<(), RuntimeError> {
+    register(&mut ctx, raw_type, name, false, |name| {
+        match char_size {
+            2 | 4 => Ok(TypeKind::StdWString { char_size }),
+            _ => Err(format!("{name}: unsupported wide char size {char_size}")),
+        }
+    })
+}
+
+// ----------
+//
+// __embind_register_memory_view(rawType, dataTypeIndex, name)
+pub fn __embind_register_memory_view<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    data_type_index: u32,
+    name: WasmPtr<u8, M>,
+) -> Result<(), RuntimeError> {
+    register(&mut ctx, raw_type, name, true, |name| {
+        MemoryViewType::from_index(data_type_index)
+            .map(|element| TypeKind::MemoryView { element })
+            .ok_or_else(|| format!("{name}: unknown memory view type {data_type_index}"))
+    })
+}
+
+// ----------
+//
+// __embind_register_emval(rawType, name)
+pub fn __embind_register_emval<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    name: WasmPtr<u8, M>,
+) -> Result<(), RuntimeError> {
+    register(&mut ctx, raw_type, name, false, |_| Ok(TypeKind::Emval))
+}
+
+// ----------
+//
+// __embind_register_enum(rawType, name, size, isSigned)
+pub fn __embind_register_enum<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    name: WasmPtr<u8, M>,
+    size: u32,
+    is_signed: u32,
+) -> Result<(), RuntimeError> {
+    register(&mut ctx, raw_type, name, false, |name| {
+        match check_size(name, size)? {
+            8 => Err(format!("Unknown integer type: {name}")),
+            size => Ok(TypeKind::Enum { size, signed: is_signed != 0, values: BTreeMap::new() }),
+        }
+    })
+}
+
+// ----------
+//
+// __embind_register_enum_value(rawEnumType, name, enumValue)
+pub fn __embind_register_enum_value<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_enum_type: u32,
+    name: WasmPtr<u8, M>,
+    enum_value: i32,
+) -> Result<(), RuntimeError> {
+    let name = {
+        let view = ctx.data().memory_view(&ctx);
+        read_latin1_string(&view, name.offset().into()).map_err(trap)?
+    };
+    let registry = &mut ctx.data_mut().registry;
+    match registry.types.get_mut(&raw_enum_type) {
+        Some(RegisteredType { kind: TypeKind::Enum { values, .. }, .. }) => {
+            values.insert(name, enum_value);
+            Ok(())
+        },
+        Some(other) => Err(trap(format!("enum value {name}: {} is not an enum", other.name))),
+        None => Err(trap(format!("enum has unknown type {raw_enum_type:#x}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{Function, Memory32};
+    use crate::env::testing::{call, env_with_memory, write_c_string};
+    use super::*;
+
+    fn integer(size: u32, signed: bool) -> RegisteredType {
+        RegisteredType { name: "int".to_string(), kind: TypeKind::Integer { size, signed, min: 0, max: 0 } }
+    }
+
+    fn byte_enum(signed: bool) -> RegisteredType {
+        let values = BTreeMap::from([("LAST".to_string(), 255)]);
+        RegisteredType { name: "Enum".to_string(), kind: TypeKind::Enum { size: 1, signed, values } }
+    }
+
+    #[test]
+    fn unsigned_narrow_ints_from_wire() {
+        let (store, env) = env_with_memory();
+        let view = env.as_ref(&store).memory_view(&store);
+        let all_ones = Value::I32(-1);
+        assert_eq!(integer(1, false).from_wire(&view, &all_ones), Ok(EmValue::Number(255.0)));
+        assert_eq!(integer(2, false).from_wire(&view, &all_ones), Ok(EmValue::Number(65535.0)));
+        assert_eq!(integer(4, false).from_wire(&view, &all_ones), Ok(EmValue::Number(4294967295.0)));
+        assert_eq!(integer(1, true).from_wire(&view, &all_ones), Ok(EmValue::Number(-1.0)));
+        // the bits above a narrow type are not cleared on the wire
+        assert_eq!(integer(1, false).from_wire(&view, &Value::I32(0x1234)), Ok(EmValue::Number(0x34 as f64)));
+        assert!(integer(4, false).from_wire(&view, &Value::I64(1)).is_err());
+    }
+
+    #[test]
+    fn unsigned_narrow_ints_from_pointer() {
+        let (store, env) = env_with_memory();
+        let view = env.as_ref(&store).memory_view(&store);
+        view.write(16, &[0xff; 4]).unwrap();
+        assert_eq!(integer(1, false).read_from_pointer(&view, 16), Ok(EmValue::Number(255.0)));
+        assert_eq!(integer(2, false).read_from_pointer(&view, 16), Ok(EmValue::Number(65535.0)));
+        assert_eq!(integer(4, false).read_from_pointer(&view, 16), Ok(EmValue::Number(4294967295.0)));
+        assert_eq!(integer(1, true).read_from_pointer(&view, 16), Ok(EmValue::Number(-1.0)));
+        assert_eq!(integer(2, true).read_from_pointer(&view, 16), Ok(EmValue::Number(-1.0)));
+        assert_eq!(byte_enum(false).read_from_pointer(&view, 16), Ok(EmValue::Number(255.0)));
+        // read as -1, which is not a value of the enum
+        assert!(byte_enum(true).read_from_pointer(&view, 16).is_err());
+    }
+
+    #[test]
+    fn registers_types() {
+        let (mut store, env) = env_with_memory();
+        write_c_string(&store, &env, 64, "unsigned char");
+        let register_integer = Function::new_typed_with_env(&mut store, &env, __embind_register_integer::<Memory32>);
+        call(&mut store, &register_integer, &[8, 64, 1, 0, 255]).unwrap();
+        let expected = RegisteredType {
+            name: "unsigned char".to_string(),
+            kind: TypeKind::Integer { size: 1, signed: false, min: 0, max: 255 },
+        };
+        assert_eq!(env.as_ref(&store).registry.get(8), Some(&expected));
+        assert_eq!(env.as_ref(&store).registry.find_by_name("unsigned char"), Some((8, &expected)));
+
+        let twice = call(&mut store, &register_integer, &[8, 64, 1, 0, 255]).unwrap_err();
+        assert!(twice.contains("Cannot register type 'unsigned char' twice"), "{twice}");
+        let null = call(&mut store, &register_integer, &[0, 64, 1, 0, 255]).unwrap_err();
+        assert!(null.contains("positive integer typeid pointer"), "{null}");
+        let size = call(&mut store, &register_integer, &[12, 64, 3, 0, 255]).unwrap_err();
+        assert!(size.contains("Unknown type size: 3"), "{size}");
+        assert_eq!(env.as_ref(&store).registry.len(), 1);
+
+        // every memory_view<T> instance registers the same type
+        write_c_string(&store, &env, 64, "emscripten::memory_view<unsigned char>");
+        let register_view = Function::new_typed_with_env(&mut store, &env, __embind_register_memory_view::<Memory32>);
+        call(&mut store, &register_view, &[16, 1, 64]).unwrap();
+        call(&mut store, &register_view, &[16, 1, 64]).unwrap();
+        let kind = &env.as_ref(&store).registry.get(16).unwrap().kind;
+        assert_eq!(kind, &TypeKind::MemoryView { element: MemoryViewType::U8 });
+        assert!(call(&mut store, &register_view, &[20, 8, 64]).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+//! Host side of `emscripten::val`
+//!
+//! C++ code refers to host values through small integer handles. The JS
+//! glue keeps them in `emval_handle_array` with a free list and refcounts;
+//! `HandleTable` does the same for `EmValue`s and for Rust closures, so
+//! that the guest can call back into the host (e.g. progress callbacks).
+
+use std::fmt;
+use wasmer::{FunctionEnvMut, MemorySize, RuntimeError, WasmPtr};
+use crate::embind::EmValue;
+use crate::env::{EmscriptenEnv, read_u32, trap};
+use crate::invoke;
+
+/// Handles 1 - 4 are the constants `undefined`, `null`, `true` and `false`
+pub const UNDEFINED: u32 = 1;
+pub const NULL: u32 = 2;
+pub const TRUE: u32 = 3;
+pub const FALSE: u32 = 4;
+const FIRST_FREE_HANDLE: u32 = 5;
+
+/// Host function callable from the guest through `_emval_call`
+pub type Callback = Box<dyn FnMut(&[EmValue]) -> Result<EmValue, String> + Send>;
+
+pub enum Emval {
+    Value(EmValue),
+    Callback(Callback),
+}
+
+impl fmt::Debug for Emval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Emval::Value(v) => f.debug_tuple("Value").field(v).finish(),
+            Emval::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    refcount: u32,
+    /// `None` while a callback is being executed, see `HandleTable::call`
+    value: Option<Emval>,
+}
+
+/// `emval_handle_array` + `emval_free_list`
+#[derive(Debug, Default)]
+pub struct HandleTable {
+    entries: Vec<Option<Entry>>,
+    free_list: Vec<u32>,
+}
+
+/// Copy of a `HandleTable` that holds no callbacks, see `HandleTable::values`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HandleValues {
+    /// refcount and value of every slot
+    entries: Vec<Option<(u32, EmValue)>>,
+    free_list: Vec<u32>,
+}
+
+impl From<&HandleValues> for HandleTable {
+    fn from(values: &HandleValues) -> Self {
+        let entries = values.entries.iter()
+            .map(|e| e.as_ref().map(|(refcount, value)| Entry {
+                refcount: *refcount,
+                value: Some(Emval::Value(value.clone())),
+            }))
+            .collect();
+        Self { entries, free_list: values.free_list.clone() }
+    }
+}
+
+impl HandleTable {
+    /// `Emval.toHandle`: stores a value with a refcount of 1, or takes
+    /// another reference to an existing handle
+    pub fn to_handle(&mut self, value: EmValue) -> u32 {
+        match value {
+            EmValue::Undefined => UNDEFINED,
+            EmValue::Null => NULL,
+            EmValue::Bool(true) => TRUE,
+            EmValue::Bool(false) => FALSE,
+            EmValue::Handle(h) => {
+                // a deleted handle fails once the guest uses it
+                let _ = self.incref(h);
+                h
+            },
+            other => self.insert(Emval::Value(other)),
+        }
+    }
+
+    /// Registers a host closure, the returned handle is owned by the caller,
+    /// which passes it to the guest as a `val` argument and `decref`s it
+    /// once the call returned
+    pub fn register_callback(&mut self, callback: Callback) -> u32 {
+        self.insert(Emval::Callback(callback))
+    }
+
+    /// `Emval.toValue`: returns a copy of the value behind `handle`,
+    /// callbacks can't be copied and come back as `EmValue::Handle`
+    pub fn to_value(&self, handle: u32) -> Result<EmValue, String> {
+        match handle {
+            0 => Err(format!("Cannot use deleted val. handle = {handle}")),
+            UNDEFINED => Ok(EmValue::Undefined),
+            NULL => Ok(EmValue::Null),
+            TRUE => Ok(EmValue::Bool(true)),
+            FALSE => Ok(EmValue::Bool(false)),
+            h => match self.entry(h)?.value {
+                Some(Emval::Value(ref v)) => Ok(v.clone()),
+                Some(Emval::Callback(_)) | None => Ok(EmValue::Handle(h)),
+            },
+        }
+    }
+
+    /// `__emval_incref`
+    pub fn incref(&mut self, handle: u32) -> Result<(), String> {
+        if handle >= FIRST_FREE_HANDLE {
+            self.entry_mut(handle)?.refcount += 1;
+        }
+        Ok(())
+    }
+
+    /// `__emval_decref`, frees the slot once the refcount drops to zero
+    pub fn decref(&mut self, handle: u32) -> Result<(), String> {
+        if handle < FIRST_FREE_HANDLE {
+            return Ok(());
+        }
+        let entry = self.entry_mut(handle)?;
+        entry.refcount -= 1;
+        if entry.refcount == 0 {
+            self.entries[handle as usize] = None;
+            self.free_list.push(handle);
+        }
+        Ok(())
+    }
+
+    /// Number of live (non-constant) handles, `count_emval_handles`
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|e| e.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies all handles, fails if one of them is a host callback
+    pub fn values(&self) -> Result<HandleValues, String> {
+        let entries = self.entries.iter()
+            .enumerate()
+            .map(|(handle, e)| match e {
+                None => Ok(None),
+                Some(Entry { refcount, value: Some(Emval::Value(v)) }) => Ok(Some((*refcount, v.clone()))),
+                Some(_) => Err(format!("val handle {handle} is a host callback")),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(HandleValues { entries, free_list: self.free_list.clone() })
+    }
+
+    /// Takes the callback behind `handle` out of the table so it can run
+    /// without borrowing the env, `put_back` has to be called afterwards
+    fn take_callback(&mut self, handle: u32) -> Result<Callback, String> {
+        let entry = self.entry_mut(handle)?;
+        match entry.value.take() {
+            Some(Emval::Callback(c)) => Ok(c),
+            Some(other) => {
+                entry.value = Some(other);
+                Err(format!("val handle {handle} is not a function"))
+            },
+            None => Err(format!("val handle {handle} called recursively")),
+        }
+    }
+
+    fn put_back(&mut self, handle: u32, callback: Callback) {
+        if let Some(Some(entry)) = self.entries.get_mut(handle as usize) {
+            entry.value = Some(Emval::Callback(callback));
+        }
+    }
+
+    fn insert(&mut self, value: Emval) -> u32 {
+        if self.entries.len() < FIRST_FREE_HANDLE as usize {
+            self.entries.resize_with(FIRST_FREE_HANDLE as usize, || None);
+        }
+        let entry = Some(Entry { refcount: 1, value: Some(value) });
+        match self.free_list.pop() {
+            Some(h) => {
+                self.entries[h as usize] = entry;
+                h
+            },
+            None => {
+                self.entries.push(entry);
+                self.entries.len() as u32 - 1
+            },
+        }
+    }
+
+    fn entry(&self, handle: u32) -> Result<&Entry, String> {
+        self.entries.get(handle as usize)
+            .and_then(|e| e.as_ref())
+            .ok_or_else(|| format!("Cannot use deleted val. handle = {handle}"))
+    }
+
+    fn entry_mut(&mut self, handle: u32) -> Result<&mut Entry, String> {
+        self.entries.get_mut(handle as usize)
+            .and_then(|e| e.as_mut())
+            .ok_or_else(|| format!("Cannot use deleted val. handle = {handle}"))
+    }
+}
+
+// ----------
+//
+// __emval_incref(handle)
+pub fn __emval_incref<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    handle: u32,
+) -> Result<(), RuntimeError> {
+    ctx.data_mut().emval.incref(handle).map_err(trap)
+}
+
+// ----------
+//
+// __emval_decref(handle)
+pub fn __emval_decref<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    handle: u32,
+) -> Result<(), RuntimeError> {
+    ctx.data_mut().emval.decref(handle).map_err(trap)
+}
+
+// ----------
+//
+// __emval_take_value(type, argv) -> handle
+pub fn __emval_take_value<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    argv: WasmPtr<u8, M>,
+) -> Result<u32, RuntimeError> {
+    let env = ctx.as_ref();
+    let ty = ctx.data().registry.require(raw_type, "_emval_take_value").map_err(trap)?.clone();
+    let value = invoke::read_from_pointer(&mut ctx, &env, &ty, argv.offset().into()).map_err(trap)?;
+    Ok(ctx.data_mut().emval.to_handle(value))
+}
+
+// ----------
+//
+// __emval_call(handle, argCount, argTypes, argv) -> handle
+pub fn __emval_call<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    handle: u32,
+    arg_count: u32,
+    arg_types: WasmPtr<u8, M>,
+    argv: WasmPtr<u8, M>,
+) -> Result<u32, RuntimeError> {
+    let env = ctx.as_ref();
+
+    // __emval_lookupTypes
+    let types = {
+        let view = ctx.data().memory_view(&ctx);
+        let arg_types: u64 = arg_types.offset().into();
+        (0..arg_count as u64)
+            .map(|i| {
+                let raw = read_u32(&view, arg_types + i * 4).map_err(|e| e.to_string())?;
+                ctx.data().registry.require(raw, &format!("parameter {i}")).cloned()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(trap)?
+    };
+
+    let mut args = Vec::with_capacity(types.len());
+    let mut argv: u64 = argv.offset().into();
+    for ty in types.iter() {
+        args.push(invoke::read_from_pointer(&mut ctx, &env, ty, argv).map_err(trap)?);
+        argv += ty.arg_pack_advance();
+    }
+
+    let mut callback = ctx.data_mut().emval.take_callback(handle).map_err(trap)?;
+    let result = callback(&args);
+    ctx.data_mut().emval.put_back(handle, callback);
+    let result = result.map_err(trap)?;
+    Ok(ctx.data_mut().emval.to_handle(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_handles() {
+        let mut table = HandleTable::default();
+        assert_eq!(table.to_handle(EmValue::Undefined), UNDEFINED);
+        assert_eq!(table.to_handle(EmValue::Null), NULL);
+        assert_eq!(table.to_handle(EmValue::Bool(true)), TRUE);
+        assert_eq!(table.to_handle(EmValue::Bool(false)), FALSE);
+        for handle in UNDEFINED..=FALSE {
+            table.incref(handle).unwrap();
+            table.decref(handle).unwrap();
+            table.decref(handle).unwrap();
+        }
+        assert_eq!(table.to_value(NULL), Ok(EmValue::Null));
+        assert_eq!(table.to_value(FALSE), Ok(EmValue::Bool(false)));
+        assert!(table.to_value(0).is_err());
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn handles_are_reused() {
+        let mut table = HandleTable::default();
+        let a = table.to_handle(EmValue::Number(1.0));
+        let b = table.to_handle(EmValue::String("b".to_string()));
+        assert_eq!((a, b), (FIRST_FREE_HANDLE, FIRST_FREE_HANDLE + 1));
+        table.decref(a).unwrap();
+        assert!(table.to_value(a).unwrap_err().contains("deleted"));
+        assert!(table.decref(a).is_err());
+        assert!(table.incref(a).is_err());
+        assert_eq!(table.to_handle(EmValue::Number(2.0)), a);
+        assert_eq!(table.to_value(a), Ok(EmValue::Number(2.0)));
+        assert_eq!(table.to_handle(EmValue::Null), NULL);
+        assert_eq!(table.to_handle(EmValue::Number(3.0)), FIRST_FREE_HANDLE + 2);
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn to_handle_takes_a_reference() {
+        let mut table = HandleTable::default();
+        let handle = table.to_handle(EmValue::BigInt(7));
+        assert_eq!(table.to_handle(EmValue::Handle(handle)), handle);
+        table.decref(handle).unwrap();
+        assert_eq!(table.to_value(handle), Ok(EmValue::BigInt(7)));
+        table.decref(handle).unwrap();
+        assert!(table.to_value(handle).is_err());
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn callbacks() {
+        let mut table = HandleTable::default();
+        let handle = table.register_callback(Box::new(|args| Ok(EmValue::Number(args.len() as f64))));
+        assert_eq!(table.to_value(handle), Ok(EmValue::Handle(handle)));
+        assert!(table.values().is_err());
+
+        let mut callback = table.take_callback(handle).unwrap();
+        assert!(table.take_callback(handle).err().unwrap().contains("recursively"));
+        assert_eq!(callback(&[EmValue::Null]), Ok(EmValue::Number(1.0)));
+        table.put_back(handle, callback);
+        assert!(table.take_callback(handle).is_ok());
+
+        let value = table.to_handle(EmValue::Number(1.0));
+        assert!(table.take_callback(value).err().unwrap().contains("not a function"));
+    }
+
+    #[test]
+    fn values_roundtrip() {
+        let mut table = HandleTable::default();
+        let a = table.to_handle(EmValue::String("a".to_string()));
+        let b = table.to_handle(EmValue::Number(2.0));
+        table.decref(a).unwrap();
+        let values = table.values().unwrap();
+        let mut copy = HandleTable::from(&values);
+        assert_eq!(copy.to_value(b), Ok(EmValue::Number(2.0)));
+        assert!(copy.to_value(a).is_err());
+        assert_eq!(copy.to_handle(EmValue::Null), NULL);
+        assert_eq!(copy.to_handle(EmValue::Number(3.0)), a);
+        assert_eq!(copy.values().unwrap().entries.len(), values.entries.len());
+    }
+}
@@ -0,0 +1,679 @@
+//! Typed wrapper around the embind `OCREngine` class
+//!
+//! Mirrors the `OCREngine` class of `tesseract-worker.js`: the engine is
+//! stateful, a model and an image have to be loaded before text can be
+//! requested, and results come back as plain Rust structs.
+
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use bitflags::bitflags;
+use wasmer::{FunctionEnv, Instance, Store};
+use crate::TesseractVm;
+use crate::class;
+use crate::embind::{EmValue, TypeRegistry};
+use crate::emval::Callback;
+use crate::env::EmscriptenEnv;
+use crate::error::OcrError;
+use crate::interrupt::{self, CancellationToken};
+use crate::options::TESSDATA_DIR;
+use crate::output::GuestOutput;
+use crate::snapshot::EngineSnapshot;
+use crate::tessdata;
+
+/// Granularity of the boxes returned by `get_bounding_boxes` / `get_text_boxes`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TextUnit {
+    Word,
+    Line,
+}
+
+impl TextUnit {
+    /// Value of `TextUnit::Word` / `TextUnit::Line` on the C++ side,
+    /// verified against the module by `check_enums`
+    fn wire_value(&self) -> i32 {
+        match self {
+            TextUnit::Word => 0,
+            TextUnit::Line => 1,
+        }
+    }
+}
+
+bitflags! {
+    /// Position of a word or line within its text line, `LayoutFlags` on the C++ side
+    #[derive(Default)]
+    pub struct LayoutFlags: u32 {
+        const START_OF_LINE = 1;
+        const END_OF_LINE = 2;
+    }
+}
+
+/// Pixel coordinates of a box, `right` and `bottom` are exclusive
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct IntRect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+/// Location of a word or line found by layout analysis
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BoxItem {
+    pub rect: IntRect,
+    pub flags: LayoutFlags,
+}
+
+/// Location and recognized content of a word or line
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TextItem {
+    pub rect: IntRect,
+    pub flags: LayoutFlags,
+    /// 0.0 - 1.0
+    pub confidence: f32,
+    pub text: String,
+}
+
+/// Estimated orientation of the page
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Orientation {
+    /// clockwise rotation of the page in degrees
+    pub rotation: i32,
+    pub confidence: f32,
+}
+
+/// A stateful tesseract instance, see `OCREngine` in `tesseract-worker.js`
+pub struct OcrEngine {
+    store: Store,
+    env: FunctionEnv<EmscriptenEnv>,
+    instance: Instance,
+    /// `OCREngine*` on the guest heap
+    engine: u32,
+    model_loaded: bool,
+    image_loaded: bool,
+    /// a call trapped, threw, ran out of memory or was interrupted, the
+    /// guest state is undefined
+    poisoned: bool,
+    /// limit of every call, see `set_timeout`
+    timeout: Option<Duration>,
+    /// `progressChannel`, see `set_progress_channel`
+    progress_channel: Option<Sender<u32>>,
+}
+
+impl OcrEngine {
+    /// Instantiates the module and constructs a new `OCREngine` inside of it
+    pub fn new(vm: &TesseractVm) -> Result<Self, OcrError> {
+        let mut store = vm.store();
+        let env = FunctionEnv::new(&mut store, vm.emscripten_env());
+        let instance = crate::instantiate(&mut store, &vm.module, &env, &vm.manifest)?;
+        check_enums(&env.as_ref(&store).registry)?;
+        let engine = class::construct(&mut store, &env, "OCREngine", &[])
+            .map_err(|e| OcrError::from_guest(&mut store, &env, e))?;
+        Ok(Self {
+            store,
+            env,
+            instance,
+            engine,
+            model_loaded: false,
+            image_loaded: false,
+            poisoned: false,
+            timeout: None,
+            progress_channel: None,
+        })
+    }
+
+    /// Instantiates the module in the state captured by `snapshot`, without
+    /// running its constructors or loading the model again
+    pub fn from_snapshot(vm: &TesseractVm, snapshot: &EngineSnapshot) -> Result<Self, OcrError> {
+        let mut store = vm.store();
+        let env = FunctionEnv::new(&mut store, vm.emscripten_env());
+        let instance = crate::link(&mut store, &vm.module, &env, &vm.manifest)?;
+        snapshot.restore(&mut store, &instance, &env)?;
+        Ok(Self {
+            store,
+            env,
+            instance,
+            engine: snapshot.engine,
+            model_loaded: snapshot.model_loaded,
+            image_loaded: snapshot.image_loaded,
+            poisoned: false,
+            timeout: None,
+            progress_channel: None,
+        })
+    }
+
+    /// Captures the engine as it is now, typically right after `load_model`,
+    /// so that further engines can start from here with `from_snapshot`
+    ///
+    /// Fails while the guest holds progress callbacks or open files.
+    pub fn snapshot(&mut self) -> Result<EngineSnapshot, OcrError> {
+        if self.poisoned {
+            return Err(OcrError::Other("cannot snapshot an engine that trapped".to_string()));
+        }
+        EngineSnapshot::capture(
+            &mut self.store,
+            &self.instance,
+            &self.env,
+            self.engine,
+            self.model_loaded,
+            self.image_loaded,
+        )
+    }
+
+    /// Loads a trained text recognition model (the contents of a `.traineddata` file)
+    pub fn load_model(&mut self, model: &[u8]) -> Result<(), OcrError> {
+        let result = self.call("loadModel", &[EmValue::Bytes(model.to_vec())])?;
+        check_result(&result).map_err(OcrError::InvalidTrainedData)?;
+        self.model_loaded = true;
+        Ok(())
+    }
+
+    /// Loads the first of `models` (by name, e.g. `eng`) together with the
+    /// others, like `-l eng+deu` of the CLI
+    ///
+    /// Every model after the first is mounted under `TESSDATA_DIR`:
+    /// tesseract reads the other languages from there while loading the
+    /// first, and `osd` once a page segmentation mode needs it, `osd` is
+    /// never loaded as a language.
+    pub fn load_models(&mut self, models: &[(String, Cow<'_, [u8]>)]) -> Result<(), OcrError> {
+        let (first, others) = models.split_first()
+            .ok_or_else(|| OcrError::InvalidOptions("no model given".to_string()))?;
+        let fs = &mut self.env.as_mut(&mut self.store).fs;
+        for (name, data) in others {
+            let path = Path::new(TESSDATA_DIR).join(format!("{name}.traineddata"));
+            fs.mount(&path, data)
+                .map_err(|e| OcrError::Other(format!("failed to mount {}: {e:?}", path.display())))?;
+        }
+        let languages: Vec<_> = others.iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| *name != "osd")
+            .collect();
+        self.load_model(&tessdata::with_sublanguages(&first.1, &languages)?)
+    }
+
+    /// Loads an RGBA image for processing by subsequent operations
+    ///
+    /// This is cheap, expensive processing is deferred until bounding
+    /// boxes or text content are requested.
+    pub fn load_image(&mut self, width: u32, height: u32, rgba: &[u8]) -> Result<(), OcrError> {
+        if width == 0 || height == 0 {
+            return Err(OcrError::UnsupportedImage("Image width or height is zero".to_string()));
+        }
+        if (rgba.len() as u64) < width as u64 * height as u64 * 4 {
+            return Err(OcrError::UnsupportedImage("Image data length does not match width/height".to_string()));
+        }
+
+        // free the previous image first to reduce peak memory usage
+        self.clear_image()?;
+
+        let image_class = self.env.as_ref(&self.store).classes.by_name("Image")?.raw_type;
+        let image = class::construct(
+            &mut self.store,
+            &self.env,
+            "Image",
+            &[EmValue::Number(width as f64), EmValue::Number(height as f64)],
+        );
+        let image = self.guest_result(image)?;
+        let loaded = self.fill_image(image, &rgba[..width as usize * height as usize * 4])
+            .and_then(|_| self.call("loadImage", &[EmValue::Pointer { class: image_class, ptr: image }]));
+        let deleted = class::delete(&mut self.store, &self.env, "Image", image);
+        self.guest_result(deleted)?;
+        check_result(&loaded?).map_err(OcrError::UnsupportedImage)?;
+        self.image_loaded = true;
+        Ok(())
+    }
+
+    /// Frees the current image and all analysis results
+    pub fn clear_image(&mut self) -> Result<(), OcrError> {
+        self.call("clearImage", &[])?;
+        self.image_loaded = false;
+        Ok(())
+    }
+
+    /// Runs layout analysis (if not done yet) and returns the boxes of all
+    /// words or lines, does not need a model
+    pub fn get_bounding_boxes(&mut self, unit: TextUnit) -> Result<Vec<BoxItem>, OcrError> {
+        self.check_image_loaded()?;
+        let list = self.call("getBoundingBoxes", &[EmValue::Number(unit.wire_value() as f64)])?;
+        let items = self.vector_to_vec(list)?
+            .iter()
+            .map(box_item)
+            .collect::<Result<_, _>>()?;
+        Ok(items)
+    }
+
+    /// Runs layout analysis and text recognition (if not done yet) and returns
+    /// the boxes and text of all words or lines
+    pub fn get_text_boxes(&mut self, unit: TextUnit) -> Result<Vec<TextItem>, OcrError> {
+        self.get_text_boxes_with_progress(unit, |_| { })
+    }
+
+    /// `get_text_boxes`, calling `on_progress` with the percentage of the
+    /// page recognized so far
+    pub fn get_text_boxes_with_progress(
+        &mut self,
+        unit: TextUnit,
+        on_progress: impl FnMut(u32) + Send + 'static,
+    ) -> Result<Vec<TextItem>, OcrError> {
+        self.check_image_loaded()?;
+        self.check_model_loaded()?;
+        let on_progress = self.progress_callback(on_progress);
+        let list = self.call("getTextBoxes", &[EmValue::Number(unit.wire_value() as f64), EmValue::Handle(on_progress)]);
+        self.release(on_progress);
+        let list = list?;
+        let items = self.vector_to_vec(list)?
+            .iter()
+            .map(text_item)
+            .collect::<Result<_, _>>()?;
+        Ok(items)
+    }
+
+    /// Runs layout analysis and text recognition (if not done yet) and returns
+    /// the text of the page
+    pub fn get_text(&mut self) -> Result<String, OcrError> {
+        self.get_text_with_progress(|_| { })
+    }
+
+    /// `get_text`, calling `on_progress` with the percentage of the page
+    /// recognized so far
+    pub fn get_text_with_progress(&mut self, on_progress: impl FnMut(u32) + Send + 'static) -> Result<String, OcrError> {
+        self.check_image_loaded()?;
+        self.check_model_loaded()?;
+        let on_progress = self.progress_callback(on_progress);
+        let text = self.call("getText", &[EmValue::Handle(on_progress)]);
+        self.release(on_progress);
+        match text? {
+            EmValue::String(s) => Ok(s),
+            other => Err(OcrError::Other(format!("getText: expected a string, got {other:?}"))),
+        }
+    }
+
+    /// Estimates the orientation of the page, designed for non-uppercase Latin text
+    pub fn get_orientation(&mut self) -> Result<Orientation, OcrError> {
+        self.check_image_loaded()?;
+        let o = self.call("getOrientation", &[])?;
+        Ok(Orientation {
+            rotation: field(&o, "rotation").and_then(number)? as i32,
+            confidence: field(&o, "confidence").and_then(number)? as f32,
+        })
+    }
+
+    /// Whether the engine can still be used: after a trap, a C++
+    /// exception, a refused heap growth, a timeout or a cancellation the
+    /// guest was stopped halfway through a call and the instance has to be
+    /// replaced
+    pub fn is_usable(&self) -> bool {
+        !self.poisoned
+    }
+
+    /// Limits each following call (`load_image`, `get_text`, ...) to
+    /// `timeout`, calls that take longer fail with `OcrError::Timeout`
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Makes the calls of this engine fail with `OcrError::Cancelled` once
+    /// `token` is cancelled, also while one is running on another thread
+    pub fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.env.as_mut(&mut self.store).interrupt.token = token;
+    }
+
+    /// Sends the progress of every following `get_text` / `get_text_boxes`
+    /// call to `channel`, like the `progressChannel` of the JS `OCREngine`
+    pub fn set_progress_channel(&mut self, channel: Option<Sender<u32>>) {
+        self.progress_channel = channel;
+    }
+
+    /// What the guest printed since the engine was created or this was
+    /// last called, e.g. tesseract's warnings about the image
+    pub fn take_output(&mut self) -> GuestOutput {
+        self.env.as_mut(&mut self.store).fs.take_output()
+    }
+
+    /// Sets a tesseract configuration variable, see `tesseract --print-parameters`
+    pub fn set_variable(&mut self, name: &str, value: &str) -> Result<(), OcrError> {
+        let result = self.call("setVariable", &[
+            EmValue::String(name.to_string()),
+            EmValue::String(value.to_string()),
+        ])?;
+        check_result(&result).map_err(|e| OcrError::Other(format!("Failed to set variable {name}: {e}")))
+    }
+
+    /// The `(progress) => ...` callback of `getText` / `getTextBoxes`,
+    /// see `progress_reporter`; the handle has to be `release`d after the
+    /// call
+    fn progress_callback(&mut self, on_progress: impl FnMut(u32) + Send + 'static) -> u32 {
+        let callback = progress_reporter(on_progress, self.progress_channel.clone());
+        self.env.as_mut(&mut self.store).emval.register_callback(callback)
+    }
+
+    /// Drops the host's reference to a `val` handle it created
+    fn release(&mut self, handle: u32) {
+        // only fails if the guest released more than it was given, the
+        // handle is gone either way
+        let _ = self.env.as_mut(&mut self.store).emval.decref(handle);
+    }
+
+    fn call(&mut self, method: &str, args: &[EmValue]) -> Result<EmValue, OcrError> {
+        interrupt::arm(&mut self.store, &self.env, self.timeout);
+        let result = class::call_method(&mut self.store, &self.env, "OCREngine", self.engine, method, args);
+        interrupt::disarm(&mut self.store, &self.env);
+        self.guest_result(result)
+    }
+
+    /// Turns the error of a call into the guest into an `OcrError`,
+    /// picking up the exception if the call threw one
+    ///
+    /// The guest has no catch blocks, a throw traps out of the current call
+    /// without running destructors, so every error of the guest leaves the
+    /// engine unusable.
+    fn guest_result<T>(&mut self, result: Result<T, String>) -> Result<T, OcrError> {
+        match result {
+            Ok(value) => {
+                // the guest coped with a refused allocation or the host
+                // with a failed call
+                let env = self.env.as_mut(&mut self.store);
+                env.heap.refused = None;
+                env.trap.last = None;
+                env.interrupt.interrupted = None;
+                Ok(value)
+            },
+            Err(e) => {
+                let error = OcrError::from_guest(&mut self.store, &self.env, e);
+                let guest_failed = matches!(
+                    error,
+                    OcrError::Timeout(_)
+                        | OcrError::Cancelled
+                        | OcrError::Trap(_)
+                        | OcrError::OutOfMemory { .. }
+                        | OcrError::GuestException { .. }
+                );
+                if guest_failed {
+                    self.poisoned = true;
+                }
+                Err(error)
+            },
+        }
+    }
+
+    /// Copies the pixels into the `Image::data()` view of a guest image
+    fn fill_image(&mut self, image: u32, rgba: &[u8]) -> Result<(), OcrError> {
+        let data = class::call_method(&mut self.store, &self.env, "Image", image, "data", &[]);
+        let (ptr, len) = match self.guest_result(data)? {
+            EmValue::MemoryView { element, ptr, len } => (ptr, len * element.element_size()),
+            other => return Err(OcrError::Other(format!("Image.data: expected a memory view, got {other:?}"))),
+        };
+        if len as usize != rgba.len() {
+            return Err(OcrError::Other(format!("Image.data: view has {len} bytes, image has {}", rgba.len())));
+        }
+        let view = self.env.as_ref(&self.store).memory_view(&self.store);
+        view.write(ptr as u64, rgba).map_err(|e| OcrError::Other(format!("Image.data: {e}")))
+    }
+
+    /// `jsArrayFromStdVector`: copies out and deletes a returned `std::vector`
+    fn vector_to_vec(&mut self, list: EmValue) -> Result<Vec<EmValue>, OcrError> {
+        let (class, ptr) = match list {
+            EmValue::Pointer { class, ptr } => (class, ptr),
+            other => return Err(OcrError::Other(format!("expected a std::vector, got {other:?}"))),
+        };
+        let name = self.env.as_ref(&self.store).classes.get(class)
+            .map(|c| c.name.clone())
+            .ok_or_else(|| format!("vector class {class:#x} is not registered"))?;
+        let items = (|| {
+            let size = number(&class::call_method(&mut self.store, &self.env, &name, ptr, "size", &[])?)?;
+            (0..size as u32)
+                .map(|i| class::call_method(&mut self.store, &self.env, &name, ptr, "get", &[EmValue::Number(i as f64)]))
+                .collect::<Result<Vec<_>, _>>()
+        })();
+        let items = self.guest_result(items);
+        let deleted = class::delete(&mut self.store, &self.env, &name, ptr);
+        self.guest_result(deleted)?;
+        items
+    }
+
+    fn check_model_loaded(&self) -> Result<(), OcrError> {
+        if self.model_loaded { Ok(()) } else { Err(OcrError::ModelNotLoaded) }
+    }
+
+    fn check_image_loaded(&self) -> Result<(), OcrError> {
+        if self.image_loaded { Ok(()) } else { Err(OcrError::ImageNotLoaded) }
+    }
+}
+
+impl Drop for OcrEngine {
+    fn drop(&mut self) {
+        let _ = class::delete(&mut self.store, &self.env, "OCREngine", self.engine);
+    }
+}
+
+fn field<'a>(value: &'a EmValue, name: &str) -> Result<&'a EmValue, String> {
+    match value {
+        EmValue::Object(fields) => fields.get(name).ok_or_else(|| format!("missing field {name:?}")),
+        other => Err(format!("expected an object with field {name:?}, got {other:?}")),
+    }
+}
+
+fn number(value: &EmValue) -> Result<f64, String> {
+    match value {
+        EmValue::Number(n) => Ok(*n),
+        EmValue::Bool(b) => Ok(*b as u8 as f64),
+        other => Err(format!("expected a number, got {other:?}")),
+    }
+}
+
+fn layout_flags(value: &EmValue) -> Result<LayoutFlags, String> {
+    let bits = number(value)? as u32;
+    LayoutFlags::from_bits(bits).ok_or_else(|| format!("unknown layout flags {bits:#x}"))
+}
+
+fn string(value: &EmValue) -> Result<String, String> {
+    match value {
+        EmValue::String(s) => Ok(s.clone()),
+        other => Err(format!("expected a string, got {other:?}")),
+    }
+}
+
+/// Forwards the percentages tesseract reports to `on_progress` and
+/// `channel`, each one once and only if it is higher than the last
+fn progress_reporter(mut on_progress: impl FnMut(u32) + Send + 'static, channel: Option<Sender<u32>>) -> Callback {
+    let mut last = None;
+    Box::new(move |args: &[EmValue]| {
+        let progress = args.first().map(number).transpose()?.unwrap_or(0.0).clamp(0.0, 100.0) as u32;
+        if last.map_or(true, |last| progress > last) {
+            last = Some(progress);
+            on_progress(progress);
+            if let Some(channel) = &channel {
+                // the receiver may have lost interest, recognition goes on
+                let _ = channel.send(progress);
+            }
+        }
+        Ok(EmValue::Undefined)
+    })
+}
+
+/// Verifies that the enums registered by the module still have the values
+/// hard-coded above, so that a rebuilt wasm with renumbered enums fails to
+/// load instead of returning wrong results
+fn check_enums(registry: &TypeRegistry) -> Result<(), String> {
+    check_enum(registry, "TextUnit", true, &[
+        ("Word", TextUnit::Word.wire_value()),
+        ("Line", TextUnit::Line.wire_value()),
+    ])?;
+    // the JS wrapper defines the flags itself, older builds don't export them
+    check_enum(registry, "LayoutFlags", false, &[
+        ("StartOfLine", LayoutFlags::START_OF_LINE.bits() as i32),
+        ("EndOfLine", LayoutFlags::END_OF_LINE.bits() as i32),
+    ])
+}
+
+fn check_enum(registry: &TypeRegistry, name: &str, required: bool, expected: &[(&str, i32)]) -> Result<(), String> {
+    let values = match registry.enum_values(name) {
+        Some(values) => values,
+        None if required => return Err(format!("enum {name} is not registered by the module")),
+        None => return Ok(()),
+    };
+    for (value_name, value) in expected {
+        match values.get(*value_name) {
+            Some(v) if v == value => { },
+            Some(v) => return Err(format!("{name}.{value_name} is {v} in the module, expected {value}")),
+            None => return Err(format!("{name}.{value_name} is not registered by the module")),
+        }
+    }
+    Ok(())
+}
+
+/// `OCRResult`: an empty or missing `error` means success
+fn check_result(value: &EmValue) -> Result<(), String> {
+    match value {
+        EmValue::Object(fields) => match fields.get("error") {
+            Some(EmValue::String(e)) if !e.is_empty() => Err(e.clone()),
+            _ => Ok(()),
+        },
+        EmValue::Undefined => Ok(()),
+        other => Err(format!("unexpected result {other:?}")),
+    }
+}
+
+fn int_rect(value: &EmValue) -> Result<IntRect, String> {
+    Ok(IntRect {
+        left: field(value, "left").and_then(number)? as i32,
+        top: field(value, "top").and_then(number)? as i32,
+        right: field(value, "right").and_then(number)? as i32,
+        bottom: field(value, "bottom").and_then(number)? as i32,
+    })
+}
+
+fn box_item(value: &EmValue) -> Result<BoxItem, String> {
+    Ok(BoxItem {
+        rect: field(value, "rect").and_then(int_rect)?,
+        flags: field(value, "flags").and_then(layout_flags)?,
+    })
+}
+
+fn text_item(value: &EmValue) -> Result<TextItem, String> {
+    Ok(TextItem {
+        rect: field(value, "rect").and_then(int_rect)?,
+        flags: field(value, "flags").and_then(layout_flags)?,
+        confidence: field(value, "confidence").and_then(number)? as f32,
+        text: field(value, "text").and_then(string)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex, mpsc};
+    use crate::embind::{RegisteredType, TypeKind};
+    use super::*;
+
+    fn object(fields: &[(&str, EmValue)]) -> EmValue {
+        EmValue::Object(fields.iter().map(|(k, v)| (k.to_string(), v.clone())).collect::<BTreeMap<_, _>>())
+    }
+
+    fn rect() -> EmValue {
+        object(&[
+            ("left", EmValue::Number(1.0)),
+            ("top", EmValue::Number(2.0)),
+            ("right", EmValue::Number(30.0)),
+            ("bottom", EmValue::Number(40.0)),
+        ])
+    }
+
+    #[test]
+    fn checks_results() {
+        assert_eq!(check_result(&EmValue::Undefined), Ok(()));
+        assert_eq!(check_result(&object(&[])), Ok(()));
+        assert_eq!(check_result(&object(&[("error", EmValue::String(String::new()))])), Ok(()));
+        let failed = object(&[("error", EmValue::String("Failed to load image".to_string()))]);
+        assert_eq!(check_result(&failed), Err("Failed to load image".to_string()));
+        assert!(check_result(&EmValue::Number(0.0)).is_err());
+    }
+
+    #[test]
+    fn decodes_items() {
+        let rect_value = IntRect { left: 1, top: 2, right: 30, bottom: 40 };
+        let item = object(&[("rect", rect()), ("flags", EmValue::Number(3.0))]);
+        let flags = LayoutFlags::START_OF_LINE | LayoutFlags::END_OF_LINE;
+        assert_eq!(box_item(&item), Ok(BoxItem { rect: rect_value, flags }));
+
+        let item = object(&[
+            ("rect", rect()),
+            ("flags", EmValue::Number(0.0)),
+            ("confidence", EmValue::Number(0.5)),
+            ("text", EmValue::String("word".to_string())),
+        ]);
+        let expected = TextItem { rect: rect_value, flags: LayoutFlags::empty(), confidence: 0.5, text: "word".to_string() };
+        assert_eq!(text_item(&item), Ok(expected));
+
+        let missing = box_item(&object(&[("rect", rect())])).unwrap_err();
+        assert!(missing.contains("missing field \"flags\""), "{missing}");
+        let wrong = object(&[
+            ("rect", rect()),
+            ("flags", EmValue::Number(0.0)),
+            ("confidence", EmValue::Number(1.0)),
+            ("text", EmValue::Null),
+        ]);
+        assert!(text_item(&wrong).unwrap_err().contains("expected a string"));
+        assert!(box_item(&EmValue::Undefined).is_err());
+    }
+
+    fn registry(enums: &[(&str, &[(&str, i32)])]) -> TypeRegistry {
+        let mut registry = TypeRegistry::default();
+        for (i, (name, values)) in enums.iter().enumerate() {
+            let values = values.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+            let kind = TypeKind::Enum { size: 4, signed: true, values };
+            registry.register(8 + i as u32 * 8, RegisteredType { name: name.to_string(), kind }).unwrap();
+        }
+        registry
+    }
+
+    #[test]
+    fn checks_enums() {
+        let text_unit: &[(&str, i32)] = &[("Word", 0), ("Line", 1)];
+        let flags: &[(&str, i32)] = &[("StartOfLine", 1), ("EndOfLine", 2)];
+        assert_eq!(check_enums(&registry(&[("TextUnit", text_unit), ("LayoutFlags", flags)])), Ok(()));
+        // older builds don't register LayoutFlags
+        assert_eq!(check_enums(&registry(&[("TextUnit", text_unit)])), Ok(()));
+
+        let missing = check_enums(&registry(&[("LayoutFlags", flags)])).unwrap_err();
+        assert_eq!(missing, "enum TextUnit is not registered by the module");
+        let renumbered = check_enums(&registry(&[("TextUnit", &[("Word", 1), ("Line", 0)])])).unwrap_err();
+        assert_eq!(renumbered, "TextUnit.Word is 1 in the module, expected 0");
+        let partial = check_enums(&registry(&[("TextUnit", text_unit), ("LayoutFlags", &flags[..1])])).unwrap_err();
+        assert_eq!(partial, "LayoutFlags.EndOfLine is not registered by the module");
+        // values the host doesn't know about are fine
+        let extended: &[(&str, i32)] = &[("Word", 0), ("Line", 1), ("Block", 2)];
+        assert_eq!(check_enums(&registry(&[("TextUnit", extended)])), Ok(()));
+    }
+
+    #[test]
+    fn decodes_layout_flags() {
+        assert_eq!(layout_flags(&EmValue::Number(1.0)), Ok(LayoutFlags::START_OF_LINE));
+        assert_eq!(layout_flags(&EmValue::Number(4.0)), Err("unknown layout flags 0x4".to_string()));
+    }
+
+    #[test]
+    fn reports_increasing_progress() {
+        let (sender, receiver) = mpsc::channel();
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let on_progress = {
+            let reported = reported.clone();
+            move |progress| reported.lock().unwrap().push(progress)
+        };
+        let mut callback = progress_reporter(on_progress, Some(sender));
+        for progress in [0.0, 10.0, 10.0, 35.5, 20.0, 100.0, 150.0] {
+            assert_eq!(callback(&[EmValue::Number(progress)]), Ok(EmValue::Undefined));
+        }
+        assert!(callback(&[EmValue::String("done".to_string())]).is_err());
+        drop(callback);
+        assert_eq!(*reported.lock().unwrap(), [0, 10, 35, 100]);
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), [0, 10, 35, 100]);
+
+        // a dropped receiver doesn't stop the callback
+        let (sender, receiver) = mpsc::channel();
+        drop(receiver);
+        let mut callback = progress_reporter(|_| { }, Some(sender));
+        assert_eq!(callback(&[EmValue::Number(50.0)]), Ok(EmValue::Undefined));
+    }
+}
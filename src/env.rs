@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use wasmer::{AsStoreRef, Function, Instance, Memory, MemoryAccessError, MemoryView, RuntimeError, Table};
+use crate::class::ClassRegistry;
+use crate::embind::{RawType, TypeRegistry};
+use crate::emval::HandleTable;
+use crate::environ::Environment;
+use crate::exception::ThrownException;
+use crate::heap::HeapEnv;
+use crate::imports::ImportManifest;
+use crate::interrupt::InterruptEnv;
+use crate::syscalls::FileTable;
+use crate::time::TimeEnv;
+use crate::trap::TrapEnv;
+use crate::value_object::StructRegistration;
+
+/// Guest functions the host needs to call back into, see the
+/// `Module["asm"][..]` assignments in the JS glue
+#[derive(Debug, Clone)]
+pub struct GuestExports {
+    pub memory: Memory,
+    pub table: Table,
+    /// `___wasm_call_ctors`, runs static constructors incl. embind registration
+    pub call_ctors: Function,
+    pub malloc: Function,
+    pub free: Function,
+    pub get_type_name: Function,
+}
+
+impl GuestExports {
+    pub fn new(instance: &Instance, manifest: &ImportManifest) -> Result<Self, String> {
+        let function = |symbol: &str| {
+            let name = manifest.export_name(symbol)?;
+            instance.exports.get_function(name)
+                .map(|f| f.clone())
+                .map_err(|e| format!("export {name:?} ({symbol}): {e}"))
+        };
+        let memory = manifest.export_name("memory")?;
+        let table = manifest.export_name("table")?;
+        Ok(Self {
+            memory: instance.exports.get_memory(memory)
+                .map(|m| m.clone())
+                .map_err(|e| format!("export {memory:?} (memory): {e}"))?,
+            table: instance.exports.get_table(table)
+                .map(|t| t.clone())
+                .map_err(|e| format!("export {table:?} (table): {e}"))?,
+            call_ctors: function("___wasm_call_ctors")?,
+            malloc: function("_malloc")?,
+            free: function("_free")?,
+            get_type_name: function("___getTypeName")?,
+        })
+    }
+}
+
+/// Host state shared by every emscripten import of one tesseract instance
+///
+/// This plays the role of the module-level globals in the emscripten JS glue
+/// (`registeredTypes`, `wasmMemory`, ...): one `EmscriptenEnv` is created per
+/// `Store` and handed to every host function of `host_functions`.
+#[derive(Debug, Default)]
+pub struct EmscriptenEnv {
+    memory: Option<Memory>,
+    exports: Option<GuestExports>,
+    /// embind types registered by the module's static constructors
+    pub registry: TypeRegistry,
+    /// embind classes, their constructors and member functions
+    pub classes: ClassRegistry,
+    /// value objects whose fields are still being registered
+    pub struct_registrations: BTreeMap<RawType, StructRegistration>,
+    /// `val` handles held by the guest
+    pub emval: HandleTable,
+    /// set by `___cxa_throw`, taken by `exception::take` once the call unwound
+    pub exception: Option<ThrownException>,
+    /// clock and time zone state of the time imports
+    pub time: TimeEnv,
+    /// open fds and working directory of the syscall imports
+    pub fs: FileTable,
+    /// heap ceiling of `_emscripten_resize_heap`
+    pub heap: HeapEnv,
+    /// variables handed out by `_environ_get`
+    pub environ: Environment,
+    /// executing host imports and the trap of the current call
+    pub trap: TrapEnv,
+    /// deadline and cancellation token of the current call
+    pub interrupt: InterruptEnv,
+    /// high half of an `i64` split by the legalizer, `setTempRet0` /
+    /// `getTempRet0`
+    pub temp_ret0: i32,
+}
+
+impl EmscriptenEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_memory(&mut self, memory: Memory) {
+        self.memory = Some(memory);
+    }
+
+    /// Returns the exported linear memory, panics if the instance
+    /// has not been wired up yet
+    pub fn memory(&self) -> &Memory {
+        self.memory.as_ref().expect("EmscriptenEnv: memory not set")
+    }
+
+    pub fn set_exports(&mut self, exports: GuestExports) {
+        self.exports = Some(exports);
+    }
+
+    pub fn exports(&self) -> Result<&GuestExports, String> {
+        self.exports.as_ref().ok_or_else(|| "guest exports not available yet".to_string())
+    }
+
+    pub fn memory_view<'a>(&'a self, store: &'a impl AsStoreRef) -> MemoryView<'a> {
+        self.memory().view(store)
+    }
+}
+
+// ----------
+//
+// HEAPU8 / HEAPU32 style accessors, all offsets are guest pointers
+
+pub fn read_bytes(view: &MemoryView, offset: u64, len: usize) -> Result<Vec<u8>, MemoryAccessError> {
+    let mut buf = vec![0_u8; len];
+    view.read(offset, &mut buf)?;
+    Ok(buf)
+}
+
+pub fn read_u16(view: &MemoryView, offset: u64) -> Result<u16, MemoryAccessError> {
+    let mut buf = [0_u8; 2];
+    view.read(offset, &mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+pub fn read_u32(view: &MemoryView, offset: u64) -> Result<u32, MemoryAccessError> {
+    let mut buf = [0_u8; 4];
+    view.read(offset, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64(view: &MemoryView, offset: u64) -> Result<u64, MemoryAccessError> {
+    let mut buf = [0_u8; 8];
+    view.read(offset, &mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub fn write_u32(view: &MemoryView, offset: u64, value: u32) -> Result<(), MemoryAccessError> {
+    view.write(offset, &value.to_le_bytes())
+}
+
+/// Reads the bytes of a NUL-terminated string, without the terminator
+pub fn read_c_string(view: &MemoryView, offset: u64) -> Result<Vec<u8>, MemoryAccessError> {
+    let mut bytes = Vec::new();
+    let mut cur = offset;
+    let mut byte = [0_u8; 1];
+    loop {
+        view.read(cur, &mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        bytes.push(byte[0]);
+        cur += 1;
+    }
+    Ok(bytes)
+}
+
+/// Reads a NUL-terminated string, mapping every byte to one char
+/// (`readLatin1String` in the JS glue, used for all embind names)
+pub fn read_latin1_string(view: &MemoryView, offset: u64) -> Result<String, MemoryAccessError> {
+    Ok(read_c_string(view, offset)?.into_iter().map(|b| b as char).collect())
+}
+
+pub fn trap(e: impl std::fmt::Display) -> RuntimeError {
+    RuntimeError::new(format!("{e}"))
+}
+
+/// Calling host functions without a module
+#[cfg(test)]
+pub mod testing {
+    use wasmer::{Function, FunctionEnv, Instance, Memory, MemoryType, Module, Store, Value, imports};
+    use super::{EmscriptenEnv, GuestExports};
+
+    /// A store and an env with one page of memory
+    pub fn env_with_memory() -> (Store, FunctionEnv<EmscriptenEnv>) {
+        let mut store = Store::default();
+        let memory = Memory::new(&mut store, MemoryType::new(1, None, false)).unwrap();
+        let mut env = EmscriptenEnv::new();
+        env.set_memory(memory);
+        let env = FunctionEnv::new(&mut store, env);
+        (store, env)
+    }
+
+    /// Writes `s` NUL-terminated to `offset`, e.g. the name of an embind type
+    pub fn write_c_string(store: &Store, env: &FunctionEnv<EmscriptenEnv>, offset: u64, s: &str) {
+        let view = env.as_ref(store).memory_view(store);
+        view.write(offset, s.as_bytes()).unwrap();
+        view.write(offset + s.len() as u64, &[0]).unwrap();
+    }
+
+    /// Calls a host function with i32 arguments, failing with the trap message
+    pub fn call(store: &mut Store, f: &Function, args: &[i32]) -> Result<(), String> {
+        let args = args.iter().map(|a| Value::I32(*a)).collect::<Vec<_>>();
+        f.call(store, &args).map(|_| ()).map_err(|e| e.message())
+    }
+
+    /// Instantiates a module without imports as the guest of a new env,
+    /// its exports named after the symbols of `GuestExports`
+    pub fn guest(wat: &str) -> (Store, FunctionEnv<EmscriptenEnv>, Instance) {
+        let mut store = Store::default();
+        let module = Module::new(&store, wat).unwrap();
+        let instance = Instance::new(&mut store, &module, &imports! {}).unwrap();
+        let function = |name: &str| instance.exports.get_function(name).unwrap().clone();
+        let exports = GuestExports {
+            memory: instance.exports.get_memory("memory").unwrap().clone(),
+            table: instance.exports.get_table("table").unwrap().clone(),
+            call_ctors: function("ctors"),
+            malloc: function("malloc"),
+            free: function("free"),
+            get_type_name: function("getTypeName"),
+        };
+        let mut env = EmscriptenEnv::new();
+        env.set_memory(exports.memory.clone());
+        env.set_exports(exports);
+        let env = FunctionEnv::new(&mut store, env);
+        (store, env, instance)
+    }
+}
@@ -0,0 +1,169 @@
+//! Environment variables of the guest
+//!
+//! `getenv` in the guest reads the `environ` block that musl copies out of
+//! `_environ_get` at startup. The variables start out as the defaults of
+//! the JS glue (`getEnvStrings`) and can be changed per `TesseractVm`.
+
+use wasmer::{FunctionEnvMut, MemorySize, RuntimeError, WasmPtr};
+use crate::env::{EmscriptenEnv, trap, write_u32};
+use crate::error::OcrError;
+use crate::options::TESSDATA_DIR;
+
+/// Ordered `KEY=value` pairs, later `set`s of a key replace its value in place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Environment {
+    vars: Vec<(String, String)>,
+}
+
+impl Default for Environment {
+    /// The emscripten defaults, plus what tesseract reads:
+    /// `DOTPRODUCT` selects the SIMD dot product (the JS glue sets it when
+    /// wasm SIMD is available), `OMP_THREAD_LIMIT` matches the
+    /// single-threaded build, `TESSDATA_PREFIX` is where the models of a
+    /// run are mounted
+    fn default() -> Self {
+        let mut env = Self { vars: Vec::new() };
+        env.insert("USER", "web_user");
+        env.insert("LOGNAME", "web_user");
+        env.insert("PATH", "/");
+        env.insert("PWD", "/");
+        env.insert("HOME", "/home/web_user");
+        env.insert("LANG", "C.UTF-8");
+        env.insert("_", "./this.program");
+        env.insert("DOTPRODUCT", "sse");
+        env.insert("OMP_THREAD_LIMIT", "1");
+        env.insert("TESSDATA_PREFIX", TESSDATA_DIR);
+        env
+    }
+}
+
+impl Environment {
+    /// Sets `key`, which must be neither empty nor contain `=` or NUL; the
+    /// `value` must not contain NUL
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), OcrError> {
+        if key.is_empty() || key.contains(['=', '\0']) {
+            return Err(OcrError::InvalidOptions(format!("invalid environment variable name {key:?}")));
+        }
+        if value.contains('\0') {
+            return Err(OcrError::InvalidOptions(format!("environment variable {key} contains NUL")));
+        }
+        self.insert(key, value);
+        Ok(())
+    }
+
+    fn insert(&mut self, key: &str, value: &str) {
+        match self.vars.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.vars.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Removes `key`, like setting `ENV[key] = undefined` in the JS glue
+    pub fn remove(&mut self, key: &str) {
+        self.vars.retain(|(k, _)| k != key);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.vars.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// The `KEY=value` strings, without terminator
+    fn strings(&self) -> impl Iterator<Item = String> + '_ {
+        self.vars.iter().map(|(k, v)| format!("{k}={v}"))
+    }
+}
+
+// ----------
+//
+// _environ_get(__environ, environ_buf) -> errno
+pub fn _environ_get<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    environ: WasmPtr<u8, M>,
+    environ_buf: WasmPtr<u8, M>,
+) -> Result<u32, RuntimeError> {
+    let view = ctx.data().memory_view(&ctx);
+    let environ: u64 = environ.offset().into();
+    let mut ptr: u64 = environ_buf.offset().into();
+    for (i, string) in ctx.data().environ.strings().enumerate() {
+        write_u32(&view, environ + i as u64 * 4, ptr as u32).map_err(trap)?;
+        let mut bytes = string.into_bytes();
+        bytes.push(0);
+        view.write(ptr, &bytes).map_err(trap)?;
+        ptr += bytes.len() as u64;
+    }
+    Ok(0)
+}
+
+// ----------
+//
+// _environ_sizes_get(penviron_count, penviron_buf_size) -> errno
+pub fn _environ_sizes_get<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    penviron_count: WasmPtr<u8, M>,
+    penviron_buf_size: WasmPtr<u8, M>,
+) -> Result<u32, RuntimeError> {
+    let view = ctx.data().memory_view(&ctx);
+    let environ = &ctx.data().environ;
+    let count = environ.strings().count();
+    let buf_size: usize = environ.strings().map(|s| s.len() + 1).sum();
+    write_u32(&view, penviron_count.offset().into(), count as u32).map_err(trap)?;
+    write_u32(&view, penviron_buf_size.offset().into(), buf_size as u32).map_err(trap)?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let env = Environment::default();
+        assert_eq!(env.get("HOME"), Some("/home/web_user"));
+        assert_eq!(env.get("OMP_THREAD_LIMIT"), Some("1"));
+        assert_eq!(env.get("TESSDATA_PREFIX"), Some("/tessdata"));
+        assert_eq!(env.get("TESSDATA"), None);
+        assert_eq!(env.strings().next().as_deref(), Some("USER=web_user"));
+    }
+
+    #[test]
+    fn overrides_in_place() {
+        let mut env = Environment::default();
+        let count = env.strings().count();
+        env.set("LANG", "de_DE.UTF-8").unwrap();
+        env.set("TESSDATA_PREFIX", "/models").unwrap();
+        env.set("DEBUG", "1").unwrap();
+        assert_eq!(env.get("LANG"), Some("de_DE.UTF-8"));
+        let strings: Vec<_> = env.strings().collect();
+        assert_eq!(strings.len(), count + 1);
+        assert_eq!(strings[5], "LANG=de_DE.UTF-8");
+        assert_eq!(strings[9], "TESSDATA_PREFIX=/models");
+        assert_eq!(strings.last().map(String::as_str), Some("DEBUG=1"));
+        env.set("EMPTY", "").unwrap();
+        assert_eq!(env.strings().last().as_deref(), Some("EMPTY="));
+    }
+
+    #[test]
+    fn removals() {
+        let mut env = Environment::default();
+        env.remove("DOTPRODUCT");
+        env.remove("NOT_SET");
+        assert_eq!(env.get("DOTPRODUCT"), None);
+        assert!(env.strings().all(|s| !s.starts_with("DOTPRODUCT=")));
+        // set again, it goes to the end
+        env.set("DOTPRODUCT", "generic").unwrap();
+        assert_eq!(env.strings().last().as_deref(), Some("DOTPRODUCT=generic"));
+        assert_ne!(env, Environment::default());
+    }
+
+    #[test]
+    fn rejects_what_environ_cannot_hold() {
+        let mut env = Environment::default();
+        for (key, value) in [("A=B", "1"), ("", "1"), ("A\0B", "1"), ("LANG", "C\0de")] {
+            let error = env.set(key, value).unwrap_err();
+            assert!(matches!(error, OcrError::InvalidOptions(_)), "{error:?}");
+        }
+        assert_eq!(env, Environment::default());
+        env.set("LANG", "a=b").unwrap();
+        assert_eq!(env.get("LANG"), Some("a=b"));
+    }
+}
@@ -0,0 +1,205 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use wasmer::{AsStoreMut, CompileError, DeserializeError, FunctionEnv, InstantiationError, SerializeError};
+use crate::env::EmscriptenEnv;
+use crate::trap::GuestTrap;
+use crate::{exception, heap, interrupt, trap};
+
+/// Error returned by `TesseractVm` and `OcrEngine`
+#[derive(Debug)]
+pub enum OcrError {
+    /// `tesseract-core.wasm` failed to compile
+    Compile(CompileError),
+    /// The compiled module could not be serialized for reuse
+    Serialize(SerializeError),
+    /// A serialized module could not be loaded
+    Deserialize(DeserializeError),
+    /// The import manifest could not be read from the JS glue or JSON
+    Manifest(String),
+    /// The module's imports or exports don't match the host, usually a
+    /// module and manifest from different builds
+    Link(String),
+    Instantiate(InstantiationError),
+    /// A file on disk could not be read or written
+    Io { path: PathBuf, source: io::Error },
+    /// `OcrOptions` that tesseract would reject, or an environment variable
+    /// the guest's `environ` can't hold
+    InvalidOptions(String),
+    /// No `.traineddata` for `languages`
+    MissingTrainedData { languages: Vec<String> },
+    /// `OcrEngine::load_model` has not been called yet
+    ModelNotLoaded,
+    /// `OcrEngine::load_image` has not been called since the last
+    /// `clear_image`
+    ImageNotLoaded,
+    /// The model passed to `OcrEngine::load_model` was rejected
+    InvalidTrainedData(String),
+    /// The image is empty, truncated or in a format tesseract can't read
+    UnsupportedImage(String),
+    /// A C++ exception escaped from tesseract. `type_name` is the mangled
+    /// `std::type_info` name (e.g. `St13runtime_error`), `what` is only
+    /// known for the standard exception types and thrown C strings
+    GuestException { type_name: String, what: Option<String> },
+    /// The guest tried to grow its heap to `requested` bytes, beyond the
+    /// `limit` set with `TesseractVm::with_max_heap_size`
+    OutOfMemory { requested: u64, limit: u64 },
+    /// The guest aborted or trapped
+    Trap(GuestTrap),
+    /// The call did not finish within the timeout
+    Timeout(Duration),
+    /// The call was stopped through its `CancellationToken`
+    Cancelled,
+    /// `OcrPool::get` was called while `limit` callers were already waiting
+    /// for an engine
+    QueueFull { limit: usize },
+    /// Any other failure of a call into the guest, e.g. an unexpected
+    /// embind type
+    Other(String),
+}
+
+impl OcrError {
+    /// The error of a failed call into the guest: an interrupt or a
+    /// refused heap growth is the root cause of whatever the guest did
+    /// next, otherwise the exception it threw or the trap it ran into
+    /// (if any)
+    pub fn from_guest(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>, e: String) -> Self {
+        // always taken, so that the exception object is released; its
+        // calls into the guest must not run into the interruption again
+        let exception = interrupt::suspended(store, env, |store| exception::take(store, env));
+        let trap = trap::take(store, env);
+        let out_of_memory = heap::take_out_of_memory(store, env);
+        interrupt::take(store, env)
+            .or(out_of_memory)
+            .or(exception)
+            .or(trap)
+            .unwrap_or(OcrError::Other(e))
+    }
+}
+
+impl fmt::Display for OcrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OcrError::Compile(_) => f.write_str("failed to compile module"),
+            OcrError::Serialize(_) => f.write_str("failed to serialize module"),
+            OcrError::Deserialize(_) => f.write_str("failed to deserialize module"),
+            OcrError::Manifest(e) => f.write_str(e),
+            OcrError::Link(e) => write!(f, "failed to link module: {e}"),
+            OcrError::Instantiate(_) => f.write_str("failed to instantiate module"),
+            OcrError::Io { path, .. } => write!(f, "failed to read or write {}", path.display()),
+            OcrError::InvalidOptions(e) => write!(f, "invalid options: {e}"),
+            OcrError::MissingTrainedData { languages } => {
+                write!(f, "no trained data for {}", languages.join(", "))
+            },
+            OcrError::ModelNotLoaded => f.write_str("No text recognition model loaded"),
+            OcrError::ImageNotLoaded => f.write_str("No image loaded"),
+            OcrError::InvalidTrainedData(e) => write!(f, "Text recognition model failed to load: {e}"),
+            OcrError::UnsupportedImage(e) => write!(f, "unsupported image: {e}"),
+            OcrError::GuestException { type_name, what: Some(what) } => {
+                write!(f, "uncaught C++ exception {type_name}: {what}")
+            },
+            OcrError::GuestException { type_name, what: None } => {
+                write!(f, "uncaught C++ exception {type_name}")
+            },
+            OcrError::OutOfMemory { requested, limit } => {
+                write!(f, "out of memory: heap of {requested} bytes requested, limit is {limit} bytes")
+            },
+            OcrError::Trap(trap) => trap.fmt(f),
+            OcrError::Timeout(timeout) => write!(f, "timed out after {timeout:?}"),
+            OcrError::Cancelled => f.write_str("cancelled"),
+            OcrError::QueueFull { limit } => {
+                write!(f, "all engines are busy and {limit} callers are already waiting")
+            },
+            OcrError::Other(e) => f.write_str(e),
+        }
+    }
+}
+
+impl Error for OcrError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OcrError::Compile(e) => Some(e),
+            OcrError::Serialize(e) => Some(e),
+            OcrError::Deserialize(e) => Some(e),
+            OcrError::Instantiate(e) => Some(e),
+            OcrError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for OcrError {
+    fn from(e: String) -> Self {
+        OcrError::Other(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::testing::guest;
+    use crate::env::write_u32;
+    use crate::exception::ThrownException;
+    use crate::interrupt::{CancellationToken, Interruption};
+    use super::*;
+
+    /// A guest whose `___getTypeName` names every type `St13runtime_error`
+    /// and whose `_free` keeps the last pointer in `freed`
+    const THROWING_GUEST: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (table (export "table") 1 funcref)
+            (global $freed (export "freed") (mut i32) (i32.const 0))
+            (func (export "ctors"))
+            (func (export "malloc") (param i32) (result i32) (i32.const 4096))
+            (func (export "free") (param i32) (global.set $freed (local.get 0)))
+            (func (export "getTypeName") (param i32) (result i32) (i32.const 512))
+            (data (i32.const 512) "St13runtime_error\00")
+            (data (i32.const 600) "no page\00"))
+    "#;
+
+    const EXCEPTION: ThrownException = ThrownException { ptr: 2048, type_info: 16, destructor: 0 };
+
+    #[test]
+    fn takes_thrown_exceptions() {
+        let (mut store, env, instance) = guest(THROWING_GUEST);
+        {
+            let view = env.as_ref(&store).memory_view(&store);
+            // the `__libcpp_refstring` of the `runtime_error`
+            write_u32(&view, EXCEPTION.ptr as u64 + 4, 600).unwrap();
+        }
+        env.as_mut(&mut store).exception = Some(EXCEPTION);
+        let error = OcrError::from_guest(&mut store, &env, "unreachable".to_string());
+        match error {
+            OcrError::GuestException { type_name, what } => {
+                assert_eq!((type_name.as_str(), what.as_deref()), ("St13runtime_error", Some("no page")));
+            },
+            other => panic!("{other:?}"),
+        }
+        let freed = instance.exports.get_global("freed").unwrap().get(&mut store);
+        assert_eq!(freed.i32(), Some(2048 - 24));
+        assert!(env.as_ref(&store).exception.is_none());
+    }
+
+    #[test]
+    fn releases_exceptions_of_interrupted_calls() {
+        let (mut store, env, instance) = guest(THROWING_GUEST);
+        let token = CancellationToken::new();
+        token.cancel();
+        let interrupt = &mut env.as_mut(&mut store).interrupt;
+        interrupt.token = Some(token);
+        interrupt.interrupted = Some(Interruption::Cancelled);
+        env.as_mut(&mut store).exception = Some(EXCEPTION);
+
+        // the interruption is the root cause, but the exception is still
+        // named and freed through the guest
+        let error = OcrError::from_guest(&mut store, &env, "cancelled".to_string());
+        assert!(matches!(error, OcrError::Cancelled), "{error:?}");
+        let freed = instance.exports.get_global("freed").unwrap().get(&mut store);
+        assert_eq!(freed.i32(), Some(2048 - 24));
+        assert!(env.as_ref(&store).exception.is_none());
+        // the token stays, later calls are cancelled as well
+        assert!(env.as_mut(&mut store).interrupt.check().is_err());
+    }
+}
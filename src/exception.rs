@@ -0,0 +1,135 @@
+//! C++ exceptions thrown by the guest
+//!
+//! The module is built without exception catching, so a `throw` always
+//! unwinds the whole call: `___cxa_throw` records the exception in the env
+//! and traps, and once the call has returned `take` turns the recorded
+//! exception into an `OcrError::GuestException` and releases it.
+//!
+//! A throw is fatal for the instance all the same. No destructors ran for
+//! the unwound frames and the stack pointer stays where they left it, so
+//! the engine is poisoned like after any other trap.
+
+use wasmer::{AsStoreMut, FunctionEnv, FunctionEnvMut, MemorySize, RuntimeError, Value};
+use crate::class::FunctionPtr;
+use crate::env::{EmscriptenEnv, read_c_string, read_latin1_string, read_u32, trap, write_u32};
+use crate::error::OcrError;
+use crate::invoke;
+use crate::trap;
+
+/// Size of the `ExceptionInfo` header in front of every exception object
+const EXCEPTION_INFO_SIZE: u32 = 24;
+
+/// libc++ exception types whose message is a `__libcpp_refstring` right
+/// after the vtable pointer
+const REFSTRING_EXCEPTIONS: &[&str] = &[
+    "St11logic_error",
+    "St12domain_error",
+    "St16invalid_argument",
+    "St12length_error",
+    "St12out_of_range",
+    "St13runtime_error",
+    "St11range_error",
+    "St14overflow_error",
+    "St15underflow_error",
+];
+
+/// An exception thrown by the call in progress, see `ExceptionInfo` in the JS glue
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ThrownException {
+    /// the exception object, `EXCEPTION_INFO_SIZE` bytes after the malloc'ed block
+    pub ptr: u32,
+    /// `std::type_info*`
+    pub type_info: u32,
+    /// destructor of the exception object, 0 for trivially destructible types
+    pub destructor: u32,
+}
+
+/// Takes the exception recorded by `___cxa_throw` (if any), decodes its
+/// type and message and frees it
+///
+/// Must only be called after the throwing call has returned.
+pub fn take(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>) -> Option<OcrError> {
+    let exception = env.as_mut(store).exception.take()?;
+    let type_name = type_name(store, env, exception.type_info)
+        .unwrap_or_else(|_| format!("{:#x}", exception.type_info));
+    let what = what(store, env, &type_name, exception.ptr);
+    // the object is unreachable for the guest now, a failure to release
+    // it only leaks memory
+    let _ = release(store, env, &exception);
+    Some(OcrError::GuestException { type_name, what })
+}
+
+/// `getTypeName`: `___getTypeName` returns a malloc'ed copy of the name
+fn type_name(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>, type_info: u32) -> Result<String, String> {
+    let get_type_name = env.as_ref(&*store).exports()?.get_type_name.clone();
+    let ptr = match trap::call(store, env, &get_type_name, &[Value::I32(type_info as i32)])
+        .map_err(|e| format!("___getTypeName: {e}"))?
+        .first()
+    {
+        Some(Value::I32(ptr)) => *ptr as u32,
+        other => return Err(format!("___getTypeName: unexpected return value {other:?}")),
+    };
+    let name = {
+        let view = env.as_ref(&*store).memory_view(&*store);
+        read_latin1_string(&view, ptr as u64).map_err(|e| e.to_string())
+    };
+    invoke::free(store, env, ptr)?;
+    name
+}
+
+/// Best effort `what()`, the virtual call is not exported by the module
+fn what(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>, type_name: &str, ptr: u32) -> Option<String> {
+    let view = env.as_ref(&*store).memory_view(&*store);
+    let message = match type_name {
+        // `throw "..."`: the object is the pointer
+        "PKc" | "Pc" => read_u32(&view, ptr as u64).ok()?,
+        t if REFSTRING_EXCEPTIONS.contains(&t) => read_u32(&view, ptr as u64 + 4).ok()?,
+        _ => return None,
+    };
+    let bytes = read_c_string(&view, message as u64).ok()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// `___cxa_free_exception`: runs the destructor and frees the block
+fn release(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>, exception: &ThrownException) -> Result<(), String> {
+    if exception.destructor != 0 {
+        let destructor = FunctionPtr { signature: "vi".to_string(), index: exception.destructor };
+        invoke::call_raw(store, env, &destructor, &[exception.ptr])?;
+    }
+    invoke::free(store, env, exception.ptr - EXCEPTION_INFO_SIZE)
+}
+
+// ----------
+//
+// ___cxa_allocate_exception(size) -> ptr
+pub fn ___cxa_allocate_exception<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    size: u32,
+) -> Result<u32, RuntimeError> {
+    let env = ctx.as_ref();
+    let info = invoke::malloc(&mut ctx, &env, size + EXCEPTION_INFO_SIZE).map_err(trap)?;
+    Ok(info + EXCEPTION_INFO_SIZE)
+}
+
+// ----------
+//
+// ___cxa_throw(ptr, type, destructor)
+pub fn ___cxa_throw<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    ptr: u32,
+    type_info: u32,
+    destructor: u32,
+) -> Result<(), RuntimeError> {
+    {
+        // ExceptionInfo.init: refcount, type, destructor, caught / rethrown, adjusted ptr
+        let view = ctx.data().memory_view(&ctx);
+        let info = (ptr - EXCEPTION_INFO_SIZE) as u64;
+        write_u32(&view, info, 0).map_err(trap)?;
+        write_u32(&view, info + 4, type_info).map_err(trap)?;
+        write_u32(&view, info + 8, destructor).map_err(trap)?;
+        write_u32(&view, info + 12, 0).map_err(trap)?;
+        write_u32(&view, info + 16, 0).map_err(trap)?;
+    }
+    ctx.data_mut().exception = Some(ThrownException { ptr, type_info, destructor });
+    Err(trap(format!("uncaught C++ exception at {ptr:#x}")))
+}
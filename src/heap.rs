@@ -0,0 +1,133 @@
+//! Growth of the guest heap
+//!
+//! The module is built with `ALLOW_MEMORY_GROWTH`: once dlmalloc runs out of
+//! linear memory it calls `_emscripten_resize_heap`, which grows the
+//! exported memory up to the ceiling of the `TesseractVm`. A refused
+//! request makes `malloc` return NULL inside the guest; the request is
+//! remembered so that the failing call can be reported as
+//! `OcrError::OutOfMemory` instead of whatever the guest makes of it.
+
+use wasmer::{AsStoreMut, FunctionEnv, FunctionEnvMut, MemorySize, Pages, RuntimeError, WasmPtr};
+use crate::env::{EmscriptenEnv, read_bytes, trap};
+use crate::error::OcrError;
+
+/// `_emscripten_get_heap_max()` of the bundled build
+pub const DEFAULT_MAX_HEAP_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Size of a wasm page
+pub const PAGE_SIZE: u64 = 65536;
+
+/// Heap limit of one instance and the last request it refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapEnv {
+    /// maximum size of the linear memory in bytes, a multiple of `PAGE_SIZE`
+    pub max_size: u64,
+    /// size requested by the last refused `_emscripten_resize_heap`
+    pub refused: Option<u64>,
+}
+
+impl HeapEnv {
+    /// Limit of `max_size` rounded down to whole pages, the memory can't
+    /// grow by less
+    pub fn new(max_size: u64) -> Self {
+        Self { max_size: max_size - max_size % PAGE_SIZE, refused: None }
+    }
+}
+
+impl Default for HeapEnv {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_HEAP_SIZE)
+    }
+}
+
+/// Takes the request refused during the last call (if any) as an
+/// `OcrError::OutOfMemory`
+pub fn take_out_of_memory(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>) -> Option<OcrError> {
+    let heap = &mut env.as_mut(store).heap;
+    let requested = heap.refused.take()?;
+    Some(OcrError::OutOfMemory { requested, limit: heap.max_size })
+}
+
+fn align_up(x: u64, multiple: u64) -> u64 {
+    x + (multiple - x % multiple) % multiple
+}
+
+// ----------
+//
+// _emscripten_resize_heap(requestedSize) -> bool
+pub fn _emscripten_resize_heap<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    requested_size: u32,
+) -> u32 {
+    let requested_size = requested_size as u64;
+    let memory = ctx.data().memory().clone();
+    let old_size = memory.view(&ctx).data_size();
+    let max_size = ctx.data().heap.max_size;
+    if requested_size > max_size {
+        ctx.data_mut().heap.refused = Some(requested_size);
+        return 0;
+    }
+    // overgrow by 20% (10%, 5%) but at most 96 MB, like the JS glue
+    for cut_down in [1, 2, 4] {
+        let over_grown = old_size + old_size / (5 * cut_down);
+        let over_grown = over_grown.min(requested_size + 96 * 1024 * 1024);
+        let new_size = align_up(requested_size.max(over_grown), PAGE_SIZE).min(max_size);
+        let delta = (new_size.saturating_sub(old_size) + PAGE_SIZE - 1) / PAGE_SIZE;
+        if memory.grow(&mut ctx, Pages(delta as u32)).is_ok() {
+            return 1;
+        }
+    }
+    ctx.data_mut().heap.refused = Some(requested_size);
+    0
+}
+
+// ----------
+//
+// _emscripten_memcpy_big(dest, src, num)
+pub fn _emscripten_memcpy_big<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    dest: WasmPtr<u8, M>,
+    src: WasmPtr<u8, M>,
+    num: u32,
+) -> Result<(), RuntimeError> {
+    let view = ctx.data().memory_view(&ctx);
+    let bytes = read_bytes(&view, src.offset().into(), num as usize).map_err(trap)?;
+    view.write(dest.offset().into(), &bytes).map_err(trap)
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{Function, Memory32, Value};
+    use crate::env::testing;
+    use super::*;
+
+    fn resize(max_size: u64, pages: u32, requested: u64) -> (bool, u64, Option<u64>) {
+        let (mut store, env) = testing::env_with_memory();
+        env.as_mut(&mut store).heap = HeapEnv::new(max_size);
+        let memory = env.as_ref(&store).memory().clone();
+        memory.grow(&mut store, Pages(pages - 1)).unwrap();
+        let f = Function::new_typed_with_env(&mut store, &env, _emscripten_resize_heap::<Memory32>);
+        let result = f.call(&mut store, &[Value::I32(requested as i32)]).unwrap();
+        let size = memory.view(&store).data_size();
+        (result[0].i32() == Some(1), size, env.as_ref(&store).heap.refused)
+    }
+
+    #[test]
+    fn overgrows() {
+        // 20% of the 10 pages, rounded up to a page
+        assert_eq!(resize(DEFAULT_MAX_HEAP_SIZE, 10, 10 * PAGE_SIZE + 1), (true, 12 * PAGE_SIZE, None));
+        // the request if that is more
+        assert_eq!(resize(DEFAULT_MAX_HEAP_SIZE, 1, 5 * PAGE_SIZE + 1), (true, 6 * PAGE_SIZE, None));
+    }
+
+    #[test]
+    fn stops_at_the_ceiling() {
+        assert_eq!(HeapEnv::new(3 * PAGE_SIZE + 100).max_size, 3 * PAGE_SIZE);
+        // overgrowth is cut at the last whole page
+        assert_eq!(resize(11 * PAGE_SIZE + 100, 10, 10 * PAGE_SIZE + 1), (true, 11 * PAGE_SIZE, None));
+        assert_eq!(resize(3 * PAGE_SIZE + 100, 3, 3 * PAGE_SIZE), (true, 3 * PAGE_SIZE, None));
+        // a request in the partial page can't be met
+        let requested = 3 * PAGE_SIZE + 50;
+        assert_eq!(resize(3 * PAGE_SIZE + 100, 3, requested), (false, 3 * PAGE_SIZE, Some(requested)));
+    }
+}
@@ -0,0 +1,218 @@
+//! Minified import / export names of an emscripten build
+//!
+//! emscripten's minifier renames the keys of `asmLibraryArg` ("a", "b", ...)
+//! and the module's exports ("W", "X", ...) on every build. Instead of
+//! re-deriving the letters by hand, an `ImportManifest` is read from the JS glue
+//! emitted next to the wasm (or from a JSON sidecar with the same content)
+//! and `build_imports` checks every import against the module's type section.
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use wasmer::{ExternType, FunctionEnv, Imports, Module, Store};
+use crate::env::EmscriptenEnv;
+use crate::error::OcrError;
+
+/// Import namespace of all emscripten imports (`info = { "a": asmLibraryArg }`)
+pub const IMPORT_NAMESPACE: &str = "a";
+
+/// Name maps of one build of `tesseract-core.wasm`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportManifest {
+    /// minified import name -> symbol, e.g. `"a" -> "___cxa_throw"`
+    pub imports: BTreeMap<String, String>,
+    /// symbol -> minified export name, e.g. `"_malloc" -> "Y"`;
+    /// the linear memory and the function table are keyed as
+    /// `"memory"` and `"table"`
+    pub exports: BTreeMap<String, String>,
+}
+
+impl ImportManifest {
+    /// Reads the name maps out of the emscripten JS glue: the
+    /// `asmLibraryArg` object and the `Module["asm"]["X"]` accessors
+    pub fn from_js_glue(js: &str) -> Result<Self, OcrError> {
+        Self::parse_js_glue(js).map_err(OcrError::Manifest)
+    }
+
+    fn parse_js_glue(js: &str) -> Result<Self, String> {
+        let start = ["var asmLibraryArg = {", "var wasmImports = {"].iter()
+            .find_map(|p| js.find(p).map(|i| i + p.len()))
+            .ok_or_else(|| "JS glue: no asmLibraryArg object found".to_string())?;
+        let body = &js[start..];
+        let body = &body[..body.find('}').ok_or_else(|| "JS glue: unterminated asmLibraryArg".to_string())?];
+
+        let mut imports = BTreeMap::new();
+        for entry in body.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, symbol) = entry.split_once(':')
+                .ok_or_else(|| format!("JS glue: malformed import {entry:?}"))?;
+            imports.insert(unquote(name).to_string(), symbol.trim().to_string());
+        }
+
+        let mut exports = BTreeMap::new();
+        for line in js.lines() {
+            let export = match between(line, "Module[\"asm\"][\"", "\"]") {
+                Some(e) => e,
+                None => continue,
+            };
+            let symbol = if line.contains("wasmMemory =") {
+                "memory"
+            } else if line.contains("wasmTable =") {
+                "table"
+            } else {
+                match between(line, "Module[\"", "\"]") {
+                    Some(s) if s != "asm" => s,
+                    _ => continue,
+                }
+            };
+            exports.entry(symbol.to_string()).or_insert_with(|| export.to_string());
+        }
+
+        let manifest = Self { imports, exports };
+        manifest.check()?;
+        Ok(manifest)
+    }
+
+    /// Reads a JSON sidecar, `{ "imports": { .. }, "exports": { .. } }`
+    pub fn from_json(json: &str) -> Result<Self, OcrError> {
+        let manifest: Self = serde_json::from_str(json)
+            .map_err(|e| OcrError::Manifest(format!("import manifest: {e}")))?;
+        manifest.check().map_err(OcrError::Manifest)?;
+        Ok(manifest)
+    }
+
+    /// Symbol imported as `name`, e.g. `"___cxa_throw"` for `"a"`
+    pub fn import_symbol(&self, name: &str) -> Option<&str> {
+        self.imports.get(name).map(|s| s.as_str())
+    }
+
+    /// Minified name under which `symbol` is imported, e.g. `"a"` for
+    /// `"___cxa_throw"`
+    pub fn import_name(&self, symbol: &str) -> Option<&str> {
+        self.imports.iter().find(|(_, s)| *s == symbol).map(|(name, _)| name.as_str())
+    }
+
+    /// Minified name under which `symbol` is exported
+    pub fn export_name(&self, symbol: &str) -> Result<&str, String> {
+        self.exports.get(symbol)
+            .map(|s| s.as_str())
+            .ok_or_else(|| format!("import manifest: no export name for {symbol}"))
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.imports.is_empty() {
+            return Err("import manifest: no imports".to_string());
+        }
+        for symbol in ["memory", "table", "___wasm_call_ctors", "_malloc", "_free", "___getTypeName"] {
+            self.export_name(symbol)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds the import object for `module` from the host functions in
+/// `crate::host_functions`, failing if the module imports something the
+/// manifest doesn't know or with a signature the host doesn't provide
+pub fn build_imports(
+    store: &mut Store,
+    module: &Module,
+    env: &FunctionEnv<EmscriptenEnv>,
+    manifest: &ImportManifest,
+) -> Result<Imports, String> {
+    let mut functions = crate::host_functions(store, env);
+    let mut imports = Imports::new();
+    for import in module.imports() {
+        let (namespace, name) = (import.module(), import.name());
+        let expected = match import.ty() {
+            ExternType::Function(ty) => ty,
+            other => return Err(format!("import {namespace}.{name}: unsupported import type {other:?}")),
+        };
+        if namespace != IMPORT_NAMESPACE {
+            return Err(format!("import {namespace}.{name}: unknown namespace {namespace:?}"));
+        }
+        let symbol = manifest.import_symbol(name)
+            .ok_or_else(|| format!("import {namespace}.{name}: not in the import manifest"))?;
+        let function = functions.remove(symbol)
+            .ok_or_else(|| format!("import {namespace}.{name}: no host function {symbol}"))?;
+        let provided = function.ty(&*store);
+        if provided.params() != expected.params() || provided.results() != expected.results() {
+            return Err(format!(
+                "import {namespace}.{name} ({symbol}): module expects {expected}, host provides {provided}",
+            ));
+        }
+        imports.define(namespace, name, function);
+    }
+    Ok(imports)
+}
+
+fn unquote(s: &str) -> &str {
+    s.trim().trim_matches(|c| c == '"' || c == '\'')
+}
+
+/// The text between the first `start` and the following `end`
+fn between<'a>(s: &'a str, start: &str, end: &str) -> Option<&'a str> {
+    let rest = &s[s.find(start)? + start.len()..];
+    Some(&rest[..rest.find(end)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLUE: &str = r#"
+var asmLibraryArg = {
+ "a": ___cxa_throw,
+ "b": _abort,
+ 'c': _fd_write
+};
+function receiveInstance(instance, module) {
+ var exports = instance.exports;
+ Module["asm"] = exports;
+ wasmMemory = Module["asm"]["W"];
+ updateGlobalBufferAndViews(wasmMemory.buffer);
+ wasmTable = Module["asm"]["Z"];
+ addOnInit(Module["asm"]["X"]);
+}
+var ___wasm_call_ctors = Module["___wasm_call_ctors"] = function() {
+ return (___wasm_call_ctors = Module["___wasm_call_ctors"] = Module["asm"]["X"]).apply(null, arguments);
+};
+var _malloc = Module["_malloc"] = function() {
+ return (_malloc = Module["_malloc"] = Module["asm"]["Y"]).apply(null, arguments);
+};
+var _free = Module["_free"] = function() {
+ return (_free = Module["_free"] = Module["asm"]["_"]).apply(null, arguments);
+};
+var ___getTypeName = Module["___getTypeName"] = function() {
+ return (___getTypeName = Module["___getTypeName"] = Module["asm"]["$"]).apply(null, arguments);
+};
+"#;
+
+    #[test]
+    fn parses_js_glue() {
+        let manifest = ImportManifest::parse_js_glue(GLUE).unwrap();
+        assert_eq!(manifest.import_symbol("a"), Some("___cxa_throw"));
+        assert_eq!(manifest.import_symbol("c"), Some("_fd_write"));
+        assert_eq!(manifest.import_symbol("d"), None);
+        assert_eq!(manifest.export_name("memory"), Ok("W"));
+        assert_eq!(manifest.export_name("table"), Ok("Z"));
+        assert_eq!(manifest.export_name("___wasm_call_ctors"), Ok("X"));
+        assert_eq!(manifest.export_name("_free"), Ok("_"));
+        assert!(manifest.export_name("_stackSave").is_err());
+    }
+
+    #[test]
+    fn rejects_incomplete_glue() {
+        let no_imports = GLUE.replace("var asmLibraryArg", "var otherArg");
+        assert!(ImportManifest::parse_js_glue(&no_imports).unwrap_err().contains("asmLibraryArg"));
+        let empty_imports = GLUE.replace("\"a\": ___cxa_throw,\n \"b\": _abort,\n 'c': _fd_write", "");
+        assert!(ImportManifest::parse_js_glue(&empty_imports).unwrap_err().contains("no imports"));
+        let no_malloc = GLUE.replace("Module[\"_malloc\"]", "Module[\"_other\"]");
+        assert!(ImportManifest::parse_js_glue(&no_malloc).unwrap_err().contains("_malloc"));
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let manifest = ImportManifest::parse_js_glue(GLUE).unwrap();
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(ImportManifest::from_json(&json).unwrap(), manifest);
+        assert!(ImportManifest::from_json("{\"imports\": {}}").is_err());
+    }
+}
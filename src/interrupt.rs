@@ -0,0 +1,330 @@
+//! Deadlines and cancellation of calls into the guest
+//!
+//! wasmer can't stop running wasm from another thread. Every host import
+//! and every call into the guest checks the deadline and the
+//! `CancellationToken` of the instance and traps once either has expired.
+//! Tesseract calls into the host all the time (heap growth, time, the
+//! progress callback of every recognized word), so this takes effect
+//! within milliseconds.
+//!
+//! A loop that never leaves the guest is preempted by the `Preemption`
+//! middleware every module is compiled with: each loop iteration counts
+//! down a global, and every `PREEMPTION_INTERVAL` iterations the guest
+//! calls `PREEMPTION_IMPORT`, whose wrapper runs the same check. Without a
+//! deadline or token the check is two branches, and a countdown per loop
+//! iteration is much cheaper than metering every basic block.
+//!
+//! The trap unwinds the guest in the middle of whatever it was doing, the
+//! instance can't be used afterwards, see `OcrEngine::is_usable`.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use wasmer::{
+    AsStoreMut, CompilerConfig, FunctionEnv, FunctionMiddleware, GlobalInit, GlobalType,
+    LocalFunctionIndex, MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Mutability, RuntimeError, Type,
+};
+use wasmer::wasmparser::{Operator, Type as WpType, TypeOrFuncType};
+use wasmer_types::{ImportIndex, ModuleInfo};
+use crate::env::{EmscriptenEnv, trap};
+use crate::error::OcrError;
+use crate::imports::{IMPORT_NAMESPACE, ImportManifest};
+
+/// Import the guest calls to check for interrupts, a function without
+/// parameters or side effects
+pub const PREEMPTION_IMPORT: &str = "__emscripten_get_now_is_monotonic";
+
+/// Loop iterations between two calls of `PREEMPTION_IMPORT`; a host call
+/// costs about as much as a few hundred iterations of a tight loop
+const PREEMPTION_INTERVAL: i32 = 10_000;
+
+/// Cancels the guest calls of every engine or run it is handed to,
+/// clones share the same flag
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes running and future calls fail with `OcrError::Cancelled`
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancellationToken { }
+
+/// Why the current call was interrupted
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interruption {
+    Timeout(Duration),
+    Cancelled,
+}
+
+/// Deadline and cancellation state of one instance
+#[derive(Debug, Clone, Default)]
+pub struct InterruptEnv {
+    pub token: Option<CancellationToken>,
+    /// `timeout` and the point in time it expires
+    deadline: Option<(Duration, Instant)>,
+    /// set by `check` once the current call has to stop
+    pub interrupted: Option<Interruption>,
+}
+
+impl InterruptEnv {
+    /// Traps if the call has to stop, repeatedly once it did
+    pub fn check(&mut self) -> Result<(), RuntimeError> {
+        if self.interrupted.is_none() {
+            if self.token.as_ref().map_or(false, |t| t.is_cancelled()) {
+                self.interrupted = Some(Interruption::Cancelled);
+            } else if let Some((timeout, deadline)) = self.deadline {
+                if Instant::now() >= deadline {
+                    self.interrupted = Some(Interruption::Timeout(timeout));
+                }
+            }
+        }
+        match self.interrupted {
+            None => Ok(()),
+            Some(Interruption::Cancelled) => Err(trap("cancelled")),
+            Some(Interruption::Timeout(timeout)) => Err(trap(format!("timed out after {timeout:?}"))),
+        }
+    }
+}
+
+/// Adds the `Preemption` middleware for modules linked through `manifest`
+pub fn instrument(compiler: &mut impl CompilerConfig, manifest: &ImportManifest) {
+    let field = manifest.import_name(PREEMPTION_IMPORT).unwrap_or(PREEMPTION_IMPORT);
+    compiler.push_middleware(Arc::new(Preemption::new(IMPORT_NAMESPACE, field)));
+}
+
+/// Calls the import `module`.`field` every `PREEMPTION_INTERVAL` loop
+/// iterations, counted down in a global added to the module
+///
+/// Like wasmer's `Metering`, one instance instruments one module at a time.
+#[derive(Debug)]
+pub struct Preemption {
+    module: String,
+    field: String,
+    /// of the module being compiled, `None` if it lacks the import
+    indexes: Mutex<Option<PreemptionIndexes>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct PreemptionIndexes {
+    /// the global added for the countdown
+    countdown: u32,
+    /// the function index of the import
+    function: u32,
+    /// number of results of the import, dropped after the call
+    results: usize,
+}
+
+impl Preemption {
+    pub fn new(module: &str, field: &str) -> Self {
+        Self { module: module.to_string(), field: field.to_string(), indexes: Mutex::new(None) }
+    }
+}
+
+impl ModuleMiddleware for Preemption {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        let indexes = *self.indexes.lock().unwrap();
+        Box::new(FunctionPreemption { import: format!("{}.{}", self.module, self.field), indexes })
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let countdown = module_info.globals.push(GlobalType::new(Type::I32, Mutability::Var));
+        module_info.global_initializers.push(GlobalInit::I32Const(PREEMPTION_INTERVAL));
+        let function = module_info.imports.iter().find_map(|(key, index)| match index {
+            ImportIndex::Function(f) if key.module == self.module && key.field == self.field => Some(*f),
+            _ => None,
+        });
+        let indexes = function.and_then(|f| {
+            let ty = &module_info.signatures[module_info.functions[f]];
+            ty.params().is_empty().then(|| PreemptionIndexes {
+                countdown: countdown.as_u32(),
+                function: f.as_u32(),
+                results: ty.results().len(),
+            })
+        });
+        *self.indexes.lock().unwrap() = indexes;
+    }
+}
+
+#[derive(Debug)]
+struct FunctionPreemption {
+    /// `module.field` of the import, for the error if it is missing
+    import: String,
+    indexes: Option<PreemptionIndexes>,
+}
+
+impl FunctionMiddleware for FunctionPreemption {
+    fn feed<'a>(&mut self, operator: Operator<'a>, state: &mut MiddlewareReaderState<'a>) -> Result<(), MiddlewareError> {
+        let is_loop = matches!(operator, Operator::Loop { .. });
+        state.push_operator(operator);
+        if !is_loop {
+            return Ok(());
+        }
+        let PreemptionIndexes { countdown, function, results } = self.indexes.ok_or_else(|| {
+            MiddlewareError::new("Preemption", format!("no import {} without parameters", self.import))
+        })?;
+        // at the top of the loop body, so every iteration counts
+        let check = [
+            Operator::GlobalGet { global_index: countdown },
+            Operator::I32Const { value: 1 },
+            Operator::I32Sub,
+            Operator::GlobalSet { global_index: countdown },
+            Operator::GlobalGet { global_index: countdown },
+            Operator::I32Eqz,
+            Operator::If { ty: TypeOrFuncType::Type(WpType::EmptyBlockType) },
+            Operator::I32Const { value: PREEMPTION_INTERVAL },
+            Operator::GlobalSet { global_index: countdown },
+            Operator::Call { function_index: function },
+        ];
+        for operator in check {
+            state.push_operator(operator);
+        }
+        for _ in 0..results {
+            state.push_operator(Operator::Drop);
+        }
+        state.push_operator(Operator::End);
+        Ok(())
+    }
+}
+
+/// Starts the deadline of a call, `None` for no limit
+pub fn arm(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>, timeout: Option<Duration>) {
+    env.as_mut(store).interrupt.deadline = timeout.map(|t| (t, Instant::now() + t));
+}
+
+/// Ends the deadline of the call `arm` started
+pub fn disarm(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>) {
+    env.as_mut(store).interrupt.deadline = None;
+}
+
+/// Runs `f` with the deadline and cancellation token of `env` out of the
+/// way, for the calls into the guest that clean up after an interrupted call
+pub fn suspended<S: AsStoreMut, T>(store: &mut S, env: &FunctionEnv<EmscriptenEnv>, f: impl FnOnce(&mut S) -> T) -> T {
+    let interrupt = std::mem::take(&mut env.as_mut(store).interrupt);
+    let result = f(store);
+    env.as_mut(store).interrupt = interrupt;
+    result
+}
+
+/// Takes the interruption of the current call as `OcrError::Timeout` or
+/// `OcrError::Cancelled`
+pub fn take(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>) -> Option<OcrError> {
+    env.as_mut(store).interrupt.interrupted.take().map(|i| match i {
+        Interruption::Timeout(timeout) => OcrError::Timeout(timeout),
+        Interruption::Cancelled => OcrError::Cancelled,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use wasmer::{Cranelift, EngineBuilder, Function, Instance, Memory32, Module, Store, Value, imports};
+    use crate::trap;
+    use super::*;
+
+    /// `spin` loops forever without calling an import, `count` loops `n`
+    /// times; `K` is the minified name of `PREEMPTION_IMPORT`
+    const LOOPING_GUEST: &str = r#"
+        (module
+            (import "a" "K" (func (result i32)))
+            (func (export "spin") (loop $again (br $again)))
+            (func (export "count") (param $n i32) (result i32)
+                (local $i i32)
+                (loop $again
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $again (i32.lt_u (local.get $i) (local.get $n))))
+                (local.get $i)))
+    "#;
+
+    fn preempting_store(manifest: &ImportManifest) -> Store {
+        let mut compiler = Cranelift::default();
+        instrument(&mut compiler, manifest);
+        Store::new_with_engine(&EngineBuilder::new(compiler).engine())
+    }
+
+    /// The guest, its import traced like every host import
+    fn looping_guest() -> (Store, FunctionEnv<EmscriptenEnv>, Instance) {
+        let manifest = ImportManifest {
+            imports: [("K".to_string(), PREEMPTION_IMPORT.to_string())].into(),
+            ..ImportManifest::default()
+        };
+        let mut store = preempting_store(&manifest);
+        let module = Module::new(&store, LOOPING_GUEST).unwrap();
+        let env = FunctionEnv::new(&mut store, EmscriptenEnv::new());
+        let import = Function::new_typed_with_env(&mut store, &env, crate::time::__emscripten_get_now_is_monotonic::<Memory32>);
+        let import = trap::traced(&mut store, &env, PREEMPTION_IMPORT, import);
+        let instance = Instance::new(&mut store, &module, &imports! { "a" => { "K" => import } }).unwrap();
+        (store, env, instance)
+    }
+
+    fn call(
+        store: &mut Store,
+        env: &FunctionEnv<EmscriptenEnv>,
+        instance: &Instance,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Box<[Value]>, RuntimeError> {
+        let f = instance.exports.get_function(name).unwrap().clone();
+        trap::call(store, env, &f, args)
+    }
+
+    #[test]
+    fn runs_loops_without_deadline() {
+        let (mut store, env, instance) = looping_guest();
+        let n = PREEMPTION_INTERVAL * 3 + 1;
+        let result = call(&mut store, &env, &instance, "count", &[Value::I32(n)]).unwrap();
+        assert_eq!(result[0].i32(), Some(n));
+        assert!(take(&mut store, &env).is_none());
+    }
+
+    #[test]
+    fn times_out_loops() {
+        let (mut store, env, instance) = looping_guest();
+        let timeout = Duration::from_millis(50);
+        let started = Instant::now();
+        arm(&mut store, &env, Some(timeout));
+        assert!(call(&mut store, &env, &instance, "spin", &[]).is_err());
+        disarm(&mut store, &env);
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+        assert!(matches!(take(&mut store, &env), Some(OcrError::Timeout(t)) if t == timeout));
+    }
+
+    #[test]
+    fn cancels_loops() {
+        let (mut store, env, instance) = looping_guest();
+        let token = CancellationToken::new();
+        env.as_mut(&mut store).interrupt.token = Some(token.clone());
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        });
+        assert!(call(&mut store, &env, &instance, "spin", &[]).is_err());
+        canceller.join().unwrap();
+        assert!(matches!(take(&mut store, &env), Some(OcrError::Cancelled)));
+        // the token stays cancelled
+        assert!(call(&mut store, &env, &instance, "count", &[Value::I32(1)]).is_err());
+    }
+
+    #[test]
+    fn loops_need_the_import() {
+        let store = preempting_store(&ImportManifest::default());
+        assert!(Module::new(&store, "(module (func (loop)))").is_err());
+        assert!(Module::new(&store, "(module (func))").is_ok());
+    }
+}
@@ -0,0 +1,348 @@
+//! Calling into the guest through embind invokers
+//!
+//! This is the Rust counterpart of `craftInvokerFunction` and `dynCall` in
+//! the JS glue: arguments are converted to their wire representation
+//! (allocating guest memory for strings), the invoker is fetched from the
+//! indirect function table and called, temporaries are released and the
+//! result is decoded with the registered return type.
+
+use std::collections::BTreeMap;
+use wasmer::{AsStoreMut, Function, FunctionEnv, FunctionEnvMut, MemorySize, Value};
+use crate::class::{FunctionPtr, Invoker};
+use crate::embind::{EmValue, RegisteredType, TypeKind};
+use crate::env::{EmscriptenEnv, read_u32, write_u32};
+use crate::trap;
+use crate::value_object::ValueObjectField;
+
+/// Cleanup that has to run once a call returned (`runDestructors`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destructor {
+    Free(u32),
+    /// A registered `rawDestructor` applied to a temporary, e.g. a value object
+    Call(FunctionPtr, u32),
+}
+
+/// Calls an invoker with already decoded host values
+///
+/// `this` must be set for member functions and left empty for constructors.
+pub fn call(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    invoker: &Invoker,
+    this: Option<u32>,
+    args: &[EmValue],
+) -> Result<EmValue, String> {
+    if invoker.is_method != this.is_some() {
+        return Err(format!("{}: `this` given for a non-member function or vice versa", invoker.human_name));
+    }
+    if args.len() != invoker.arity() {
+        return Err(format!(
+            "function {} called with {} arguments, expected {} args!",
+            invoker.human_name, args.len(), invoker.arity(),
+        ));
+    }
+
+    let types = {
+        let registry = &env.as_ref(&*store).registry;
+        invoker.arg_types.iter()
+            .enumerate()
+            .map(|(i, t)| registry.require(*t, &format!("{} parameter {i}", invoker.human_name)).cloned())
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let skip = if invoker.is_method { 2 } else { 1 };
+
+    let mut wired = vec![Value::I32(invoker.context as i32)];
+    if let Some(this) = this {
+        wired.push(Value::I32(this as i32));
+    }
+    let mut destructors = Vec::new();
+    for (ty, arg) in types[skip..].iter().zip(args) {
+        match to_wire(store, env, ty, arg, &mut destructors) {
+            Ok(v) => wired.push(v),
+            Err(e) => {
+                run_destructors(store, env, destructors)?;
+                return Err(format!("{}: {e}", invoker.human_name));
+            },
+        }
+    }
+
+    let result = table_function(store, env, &invoker.invoker)
+        .and_then(|f| trap::call(store, env, &f, &wired).map_err(|e| format!("{}: {e}", invoker.human_name)));
+    run_destructors(store, env, destructors)?;
+    let result = result?;
+
+    match result.first() {
+        None => Ok(EmValue::Undefined),
+        Some(rv) => from_wire(store, env, &types[0], rv),
+    }
+}
+
+/// Calls a plain function pointer such as a class destructor
+pub fn call_raw(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    function: &FunctionPtr,
+    args: &[u32],
+) -> Result<Box<[Value]>, String> {
+    let f = table_function(store, env, function)?;
+    let args = args.iter().map(|a| Value::I32(*a as i32)).collect::<Vec<_>>();
+    trap::call(store, env, &f, &args).map_err(|e| format!("call_indirect {}: {e}", function.index))
+}
+
+/// `fromWireType` including the ownership rules: strings returned by the
+/// guest are freed once decoded, `val` handles are resolved and released,
+/// value objects are read through their getters and then destroyed
+pub fn from_wire(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    ty: &RegisteredType,
+    wire: &Value,
+) -> Result<EmValue, String> {
+    if let TypeKind::ValueObject { destructor, fields, .. } = &ty.kind {
+        let ptr = match wire {
+            Value::I32(ptr) => *ptr as u32,
+            other => return Err(format!("{}: expected i32 on the wire, got {other:?}", ty.name)),
+        };
+        let mut object = BTreeMap::new();
+        let mut result = Ok(());
+        for field in fields {
+            match read_field(store, env, field, ptr) {
+                Ok(v) => { object.insert(field.name.clone(), v); },
+                Err(e) => {
+                    result = Err(format!("{}.{}: {e}", ty.name, field.name));
+                    break;
+                },
+            }
+        }
+        call_raw(store, env, destructor, &[ptr])?;
+        return result.map(|_| EmValue::Object(object));
+    }
+
+    let value = {
+        let view = env.as_ref(&*store).memory_view(&*store);
+        ty.from_wire(&view, wire)?
+    };
+    match (&ty.kind, wire) {
+        (TypeKind::StdString { .. } | TypeKind::StdWString { .. }, Value::I32(ptr)) => {
+            free(store, env, *ptr as u32)?;
+        },
+        (TypeKind::Emval, _) => if let EmValue::Handle(h) = value {
+            let emval = &mut env.as_mut(store).emval;
+            let value = emval.to_value(h)?;
+            // the reference is ours, a callback that comes back as
+            // `EmValue::Handle` stays alive only through its other holders
+            emval.decref(h)?;
+            return Ok(value);
+        },
+        _ => { },
+    }
+    Ok(value)
+}
+
+/// `readValueFromPointer` with the same ownership rules as `from_wire`
+pub fn read_from_pointer(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    ty: &RegisteredType,
+    ptr: u64,
+) -> Result<EmValue, String> {
+    match ty.kind {
+        TypeKind::StdString { .. }
+        | TypeKind::StdWString { .. }
+        | TypeKind::Emval
+        | TypeKind::ValueObject { .. } => {
+            let wire = {
+                let view = env.as_ref(&*store).memory_view(&*store);
+                read_u32(&view, ptr).map_err(|e| format!("{}: {e}", ty.name))?
+            };
+            from_wire(store, env, ty, &Value::I32(wire as i32))
+        },
+        _ => {
+            let view = env.as_ref(&*store).memory_view(&*store);
+            ty.read_from_pointer(&view, ptr)
+        },
+    }
+}
+
+/// `toWireType`: strings are copied into freshly malloc'ed guest memory
+/// and value objects are constructed on the guest heap, both are released
+/// through `destructors` after the call
+pub fn to_wire(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    ty: &RegisteredType,
+    value: &EmValue,
+    destructors: &mut Vec<Destructor>,
+) -> Result<Value, String> {
+    if let TypeKind::ValueObject { constructor, destructor, fields } = &ty.kind {
+        let object = match value {
+            EmValue::Object(object) => object,
+            other => return Err(format!("Cannot pass {other:?} as value object {}", ty.name)),
+        };
+        let values = fields.iter()
+            .map(|f| object.get(&f.name).ok_or_else(|| format!("Missing field: {:?}", f.name)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ptr = match call_raw(store, env, constructor, &[])?.first() {
+            Some(Value::I32(ptr)) => *ptr as u32,
+            other => return Err(format!("{}: constructor returned {other:?}", ty.name)),
+        };
+        for (field, value) in fields.iter().zip(values) {
+            if let Err(e) = write_field(store, env, field, ptr, value, destructors) {
+                call_raw(store, env, destructor, &[ptr])?;
+                return Err(format!("{}.{}: {e}", ty.name, field.name));
+            }
+        }
+        destructors.push(Destructor::Call(destructor.clone(), ptr));
+        return Ok(Value::I32(ptr as i32));
+    }
+
+    let encoded: Vec<u8> = match (&ty.kind, value) {
+        (TypeKind::StdString { utf8: true }, EmValue::String(s)) => s.as_bytes().to_vec(),
+        (TypeKind::StdString { utf8: false }, EmValue::String(s)) => s.chars()
+            .map(|c| u8::try_from(c as u32)
+                .map_err(|_| "String has UTF-16 code units that do not fit in 8 bits".to_string()))
+            .collect::<Result<_, _>>()?,
+        (TypeKind::StdString { .. }, EmValue::Bytes(b)) => b.clone(),
+        (TypeKind::StdWString { char_size: 2 }, EmValue::String(s)) => {
+            s.encode_utf16().flat_map(|u| u.to_le_bytes()).collect()
+        },
+        (TypeKind::StdWString { .. }, EmValue::String(s)) => {
+            s.chars().flat_map(|c| (c as u32).to_le_bytes()).collect()
+        },
+        (TypeKind::StdString { .. } | TypeKind::StdWString { .. }, _) => {
+            return Err(format!("Cannot pass non-string to C++ string type {}", ty.name));
+        },
+        (TypeKind::Emval, value) => {
+            return Ok(Value::I32(env.as_mut(store).emval.to_handle(value.clone()) as i32));
+        },
+        _ => return ty.to_wire(value),
+    };
+
+    let (count, terminator) = match ty.kind {
+        TypeKind::StdWString { char_size } => (encoded.len() as u32 / char_size, char_size),
+        _ => (encoded.len() as u32, 1),
+    };
+    let ptr = malloc(store, env, 4 + encoded.len() as u32 + terminator)?;
+    {
+        let view = env.as_ref(&*store).memory_view(&*store);
+        write_u32(&view, ptr as u64, count).map_err(|e| e.to_string())?;
+        view.write(ptr as u64 + 4, &encoded).map_err(|e| e.to_string())?;
+        view.write(ptr as u64 + 4 + encoded.len() as u64, &vec![0; terminator as usize])
+            .map_err(|e| e.to_string())?;
+    }
+    destructors.push(Destructor::Free(ptr));
+    Ok(Value::I32(ptr as i32))
+}
+
+pub fn run_destructors(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    destructors: Vec<Destructor>,
+) -> Result<(), String> {
+    for d in destructors.into_iter().rev() {
+        match d {
+            Destructor::Free(ptr) => free(store, env, ptr)?,
+            Destructor::Call(function, ptr) => { call_raw(store, env, &function, &[ptr])?; },
+        }
+    }
+    Ok(())
+}
+
+/// `getterReturnType.fromWireType(getter(getterContext, ptr))`
+fn read_field(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    field: &ValueObjectField,
+    ptr: u32,
+) -> Result<EmValue, String> {
+    let ty = env.as_ref(&*store).registry.require(field.getter_return_type, &field.name)?.clone();
+    let wire = call_raw(store, env, &field.getter, &[field.getter_context, ptr])?;
+    match wire.first() {
+        Some(wire) => from_wire(store, env, &ty, wire),
+        None => Ok(EmValue::Undefined),
+    }
+}
+
+/// `setter(setterContext, ptr, setterArgumentType.toWireType(destructors, value))`
+fn write_field(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    field: &ValueObjectField,
+    ptr: u32,
+    value: &EmValue,
+    destructors: &mut Vec<Destructor>,
+) -> Result<(), String> {
+    let ty = env.as_ref(&*store).registry.require(field.setter_argument_type, &field.name)?.clone();
+    let wire = to_wire(store, env, &ty, value, destructors)?;
+    let setter = table_function(store, env, &field.setter)?;
+    trap::call(store, env, &setter, &[Value::I32(field.setter_context as i32), Value::I32(ptr as i32), wire])
+        .map_err(|e| format!("call_indirect {}: {e}", field.setter.index))?;
+    Ok(())
+}
+
+pub fn malloc(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>, size: u32) -> Result<u32, String> {
+    let malloc = env.as_ref(&*store).exports()?.malloc.clone();
+    match trap::call(store, env, &malloc, &[Value::I32(size as i32)]).map_err(|e| format!("malloc: {e}"))?.first() {
+        Some(Value::I32(0)) | None => Err(format!("malloc: failed to allocate {size} bytes")),
+        Some(Value::I32(ptr)) => Ok(*ptr as u32),
+        Some(other) => Err(format!("malloc: unexpected return value {other:?}")),
+    }
+}
+
+pub fn free(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>, ptr: u32) -> Result<(), String> {
+    let free = env.as_ref(&*store).exports()?.free.clone();
+    trap::call(store, env, &free, &[Value::I32(ptr as i32)]).map_err(|e| format!("free: {e}"))?;
+    Ok(())
+}
+
+/// `embind__requireFunction` / `getWasmTableEntry`
+pub fn table_function(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    function: &FunctionPtr,
+) -> Result<Function, String> {
+    if function.signature.contains('j') {
+        // would need the legalized dynCall_* exports
+        return Err(format!(
+            "unsupported function pointer with i64 signature {}: {}",
+            function.signature, function.index,
+        ));
+    }
+    let table = env.as_ref(&*store).exports()?.table.clone();
+    match table.get(store, function.index) {
+        Some(Value::FuncRef(Some(f))) => Ok(f),
+        _ => Err(format!(
+            "unknown function pointer with signature {}: {}",
+            function.signature, function.index,
+        )),
+    }
+}
+
+// ----------
+//
+// setTempRet0(value)
+#[allow(non_snake_case)]
+pub fn _setTempRet0<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    value: i32,
+) {
+    ctx.data_mut().temp_ret0 = value;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::testing::env_with_memory;
+    use super::*;
+
+    #[test]
+    fn rejects_i64_signatures() {
+        let (mut store, env) = env_with_memory();
+        let legalized = FunctionPtr { signature: "jij".to_string(), index: 1 };
+        let e = table_function(&mut store, &env, &legalized).err().unwrap();
+        assert!(e.contains("unsupported function pointer with i64 signature jij: 1"), "{e}");
+        // anything else gets as far as the table
+        let plain = FunctionPtr { signature: "iii".to_string(), index: 1 };
+        let e = table_function(&mut store, &env, &plain).err().unwrap();
+        assert!(e.contains("guest exports not available"), "{e}");
+    }
+}
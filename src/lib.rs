@@ -0,0 +1,400 @@
+//! Tesseract OCR compiled to wasm, run with wasmer
+//!
+//! `TesseractVm` compiles `tesseract-core.wasm` once and hands out
+//! `OcrEngine` instances, `OcrPool` keeps a number of them ready for
+//! concurrent callers.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+use wasmer::{AsStoreMut, Cranelift, Engine, EngineBuilder, Function, FunctionEnv, Instance, Memory32, Module, Store};
+use crate::env::{EmscriptenEnv, GuestExports};
+use crate::environ::Environment;
+use crate::heap::HeapEnv;
+use crate::time::TimeEnv;
+
+mod cache;
+mod class;
+mod embind;
+mod engine;
+mod emval;
+mod env;
+mod environ;
+mod error;
+mod exception;
+mod heap;
+mod imports;
+mod interrupt;
+mod invoke;
+mod options;
+mod output;
+mod pool;
+mod snapshot;
+mod syscalls;
+mod tessdata;
+mod time;
+mod trap;
+mod value_object;
+
+pub use crate::cache::ModuleCache;
+pub use crate::engine::{BoxItem, IntRect, LayoutFlags, OcrEngine, Orientation, TextItem, TextUnit};
+pub use crate::error::OcrError;
+pub use crate::imports::ImportManifest;
+pub use crate::interrupt::CancellationToken;
+pub use crate::options::{OcrOptions, OutputFormats, PageSegMode};
+pub use crate::output::{GuestOutput, OcrOutput};
+pub use crate::pool::{OcrPool, PooledEngine};
+pub use crate::snapshot::EngineSnapshot;
+pub use crate::tessdata::{EmbeddedTessdata, MemoryTessdata, TessdataDir, TessdataSource};
+pub use crate::time::{Clock, FixedClock, SystemClock};
+pub use crate::trap::GuestTrap;
+
+/// Compiler and middlewares of `new_engine`, part of the `ModuleCache` key;
+/// change it whenever the compiler configuration changes
+const COMPILER_ID: &str = "cranelift+preemption+globals";
+
+static TESSERACT_WASM: &[u8] = include_bytes!("../tesseract-core.wasm");
+/// emscripten JS glue of `TESSERACT_WASM`, source of the minified import names
+static TESSERACT_GLUE: &str = include_str!("../worker2.js");
+
+/// A compiled `tesseract-core.wasm` and the settings of its instances
+///
+/// Compiling happens once in `new`, every `ocr_image` call and every
+/// `OcrEngine` only instantiates the shared `Module` in a fresh `Store`
+/// of the same `Engine`. Cloning is cheap.
+#[derive(Clone)]
+pub struct TesseractVm {
+    engine: Engine,
+    module: Module,
+    manifest: ImportManifest,
+    clock: Arc<dyn Clock>,
+    max_heap_size: u64,
+    environ: Environment,
+    tessdata: Arc<dyn TessdataSource>,
+}
+
+impl fmt::Debug for TesseractVm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TesseractVm")
+            .field("module", &self.module)
+            .field("manifest", &self.manifest)
+            .field("clock", &self.clock)
+            .field("max_heap_size", &self.max_heap_size)
+            .field("environ", &self.environ)
+            .field("tessdata", &self.tessdata)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TesseractVm {
+    pub fn new() -> Result<Self, OcrError> {
+        Self::with_module(TESSERACT_WASM, ImportManifest::from_js_glue(TESSERACT_GLUE)?)
+    }
+
+    /// Like `new`, but loads the compiled module from `cache` if an earlier
+    /// process stored it there, see `ModuleCache`
+    pub fn new_cached(cache: &ModuleCache) -> Result<Self, OcrError> {
+        Self::with_cached_module(TESSERACT_WASM, ImportManifest::from_js_glue(TESSERACT_GLUE)?, cache)
+    }
+
+    /// Uses another build of `tesseract-core.wasm`, `manifest` has to be
+    /// read from the JS glue (or JSON sidecar) of the same build
+    pub fn with_module(wasm: &[u8], manifest: ImportManifest) -> Result<Self, OcrError> {
+        let store = Store::new_with_engine(&new_engine(&manifest));
+        let module = Module::from_binary(&store, wasm).map_err(OcrError::Compile)?;
+        Ok(Self::from_module(&store, module, manifest))
+    }
+
+    /// `with_module` going through `cache`
+    pub fn with_cached_module(wasm: &[u8], manifest: ImportManifest, cache: &ModuleCache) -> Result<Self, OcrError> {
+        let store = Store::new_with_engine(&new_engine(&manifest));
+        let module = cache.load_or_compile(&store, wasm, &manifest)?;
+        Ok(Self::from_module(&store, module, manifest))
+    }
+
+    fn from_module(store: &Store, mut module: Module, manifest: ImportManifest) -> Self {
+        module.set_name("tesseract");
+
+        Self {
+            engine: store.engine().clone(),
+            module,
+            manifest,
+            clock: Arc::new(SystemClock::new()),
+            max_heap_size: heap::DEFAULT_MAX_HEAP_SIZE,
+            environ: Environment::default(),
+            tessdata: Arc::new(EmbeddedTessdata),
+        }
+    }
+
+    /// Replaces the clock all instances read the time from,
+    /// e.g. with a `FixedClock` for reproducible output
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Limits the linear memory of every instance to `bytes` (128 MB by
+    /// default) rounded down to 64 KiB pages, calls that need more fail
+    /// with `OcrError::OutOfMemory`
+    pub fn with_max_heap_size(mut self, bytes: u64) -> Self {
+        self.max_heap_size = bytes;
+        self
+    }
+
+    /// Sets an environment variable of the guest, e.g. `DOTPRODUCT`
+    /// (`generic`, `native`, `sse`) to pick tesseract's dot product or
+    /// `LANG` for the locale
+    ///
+    /// Fails with `OcrError::InvalidOptions` if `key` is empty or contains
+    /// `=` or NUL, or `value` contains NUL.
+    pub fn env(mut self, key: &str, value: &str) -> Result<Self, OcrError> {
+        self.environ.set(key, value)?;
+        Ok(self)
+    }
+
+    /// Removes an environment variable, including the emscripten defaults
+    pub fn remove_env(mut self, key: &str) -> Self {
+        self.environ.remove(key);
+        self
+    }
+
+    /// Replaces where `ocr_image` loads its `.traineddata` files from,
+    /// the models compiled in with the `embed-*` features by default
+    pub fn with_tessdata(mut self, source: impl TessdataSource + 'static) -> Self {
+        self.tessdata = Arc::new(source);
+        self
+    }
+
+    /// Creates a new engine instance, see `OcrEngine`
+    pub fn engine(&self) -> Result<OcrEngine, OcrError> {
+        OcrEngine::new(self)
+    }
+
+    /// Host state for a new instance, configured from this VM
+    fn emscripten_env(&self) -> EmscriptenEnv {
+        let mut env = EmscriptenEnv::new();
+        env.time = TimeEnv::new(self.clock.clone());
+        env.heap = HeapEnv::new(self.max_heap_size);
+        env.environ = self.environ.clone();
+        env
+    }
+
+    /// A new store for one instance of `self.module`
+    fn store(&self) -> Store {
+        Store::new_with_engine(&self.engine)
+    }
+
+    /// Recognizes an encoded image (PNG, JPEG, TIFF, ...) with the default
+    /// options and returns the hOCR document
+    pub fn ocr_image(&self, image_data: &[u8]) -> Result<String, OcrError> {
+        let options = OcrOptions::default().outputs(OutputFormats::HOCR);
+        let output = self.ocr_image_with(image_data, &options)?;
+        output.hocr.ok_or_else(|| OcrError::Other("the run produced no hOCR".to_string()))
+    }
+
+    /// Recognizes an encoded image with a fresh engine and returns the
+    /// formats `options` ask for
+    pub fn ocr_image_with(&self, image_data: &[u8], options: &OcrOptions) -> Result<OcrOutput, OcrError> {
+        options.validate()?;
+        let image = decode_image(image_data)?;
+        let models = tessdata::load_all(&*self.tessdata, &options.required_models())?;
+
+        let started = Instant::now();
+        let mut engine = self.engine()?;
+        engine.set_cancellation_token(options.cancellation.clone());
+        recognize(&mut engine, &models, &image, options, started).map_err(|e| match (e, options.timeout) {
+            // the limit of the whole run, not of the call that hit it
+            (OcrError::Timeout(_), Some(timeout)) => OcrError::Timeout(timeout),
+            (e, _) => e,
+        })
+    }
+}
+
+/// The engine modules linked through `manifest` are compiled with, see
+/// `COMPILER_ID`
+fn new_engine(manifest: &ImportManifest) -> Engine {
+    let mut compiler = Cranelift::default();
+    interrupt::instrument(&mut compiler, manifest);
+    snapshot::instrument(&mut compiler);
+    EngineBuilder::new(compiler).engine()
+}
+
+/// Loads `models` and `image` into `engine` and produces the outputs of
+/// `options`; every call gets what is left of the run's timeout
+fn recognize(
+    engine: &mut OcrEngine,
+    models: &[(String, Cow<'_, [u8]>)],
+    image: &image::RgbaImage,
+    options: &OcrOptions,
+    started: Instant,
+) -> Result<OcrOutput, OcrError> {
+    let remaining = || match options.timeout {
+        Some(timeout) if started.elapsed() >= timeout => Err(OcrError::Timeout(timeout)),
+        timeout => Ok(timeout.map(|t| t - started.elapsed().min(t))),
+    };
+
+    engine.set_timeout(remaining()?);
+    engine.load_models(models)?;
+    for (name, value) in options.tesseract_variables() {
+        engine.set_timeout(remaining()?);
+        engine.set_variable(&name, &value)?;
+    }
+    let (width, height) = image.dimensions();
+    engine.set_timeout(remaining()?);
+    engine.load_image(width, height, image.as_raw())?;
+
+    let mut output = OcrOutput::default();
+    if options.outputs.intersects(OutputFormats::HOCR | OutputFormats::TSV) {
+        engine.set_timeout(remaining()?);
+        let words = engine.get_text_boxes(TextUnit::Word)?;
+        if options.outputs.contains(OutputFormats::HOCR) {
+            output.hocr = Some(output::hocr_from_words(width, height, &words));
+        }
+        if options.outputs.contains(OutputFormats::TSV) {
+            output.tsv = Some(output::tsv_from_words(width, height, &words));
+        }
+    }
+    if options.outputs.contains(OutputFormats::TEXT) {
+        engine.set_timeout(remaining()?);
+        output.text = Some(engine.get_text()?);
+    }
+    output.console = engine.take_output();
+    Ok(output)
+}
+
+/// Decodes an image in any of the formats of the `image` crate to RGBA
+fn decode_image(data: &[u8]) -> Result<image::RgbaImage, OcrError> {
+    if data.is_empty() {
+        return Err(OcrError::UnsupportedImage("image is empty".to_string()));
+    }
+    let image = image::load_from_memory(data).map_err(|e| OcrError::UnsupportedImage(e.to_string()))?;
+    Ok(image.to_rgba8())
+}
+
+/// Instantiates the embind build of the module: wires up the imports,
+/// the guest exports and runs the static constructors, which register
+/// all embind types and classes
+fn instantiate(
+    store: &mut Store,
+    module: &Module,
+    env: &FunctionEnv<EmscriptenEnv>,
+    manifest: &ImportManifest,
+) -> Result<Instance, OcrError> {
+
+    let instance = link(store, module, env, manifest)?;
+    let exports = env.as_ref(store).exports().map_err(OcrError::Link)?.clone();
+
+    if let Err(e) = trap::call(store, env, &exports.call_ctors, &[]) {
+        return Err(OcrError::from_guest(store, env, format!("___wasm_call_ctors: {e}")));
+    }
+
+    Ok(instance)
+}
+
+/// Instantiates `module` and wires `env` up with its exports, without
+/// running any guest code
+fn link(
+    store: &mut Store,
+    module: &Module,
+    env: &FunctionEnv<EmscriptenEnv>,
+    manifest: &ImportManifest,
+) -> Result<Instance, OcrError> {
+
+    let import_object = imports::build_imports(store, module, env, manifest).map_err(OcrError::Link)?;
+    let instance = Instance::new(store, module, &import_object).map_err(OcrError::Instantiate)?;
+    let exports = GuestExports::new(&instance, manifest).map_err(OcrError::Link)?;
+    env.as_mut(store).set_memory(exports.memory.clone());
+    env.as_mut(store).set_exports(exports);
+    Ok(instance)
+}
+
+/// All host functions by the symbol they implement, `build_imports`
+/// maps them to the module's minified import names
+fn host_functions(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>) -> BTreeMap<&'static str, Function> {
+    let mut functions = BTreeMap::new();
+    macro_rules! host {
+        ($($symbol:literal => $f:expr,)*) => {
+            $(
+                let function = Function::new_typed_with_env(store, env, $f);
+                functions.insert($symbol, trap::traced(store, env, $symbol, function));
+            )*
+        };
+    }
+    host! {
+        "___cxa_allocate_exception" => exception::___cxa_allocate_exception::<Memory32>,
+        "___cxa_throw" => exception::___cxa_throw::<Memory32>,
+        "___syscall_fcntl64" => syscalls::___syscall_fcntl64::<Memory32>,
+        "___syscall_getcwd" => syscalls::___syscall_getcwd::<Memory32>,
+        "___syscall_ioctl" => syscalls::___syscall_ioctl::<Memory32>,
+        "___syscall_openat" => syscalls::___syscall_openat::<Memory32>,
+        "___syscall_rmdir" => syscalls::___syscall_rmdir::<Memory32>,
+        "___syscall_unlinkat" => syscalls::___syscall_unlinkat::<Memory32>,
+        "__embind_finalize_value_object" => value_object::__embind_finalize_value_object::<Memory32>,
+        "__embind_register_bigint" => embind::__embind_register_bigint::<Memory32>,
+        "__embind_register_bool" => embind::__embind_register_bool::<Memory32>,
+        "__embind_register_class" => class::__embind_register_class::<Memory32>,
+        "__embind_register_class_constructor" => class::__embind_register_class_constructor::<Memory32>,
+        "__embind_register_class_function" => class::__embind_register_class_function::<Memory32>,
+        "__embind_register_emval" => embind::__embind_register_emval::<Memory32>,
+        "__embind_register_enum" => embind::__embind_register_enum::<Memory32>,
+        "__embind_register_enum_value" => embind::__embind_register_enum_value::<Memory32>,
+        "__embind_register_float" => embind::__embind_register_float::<Memory32>,
+        "__embind_register_integer" => embind::__embind_register_integer::<Memory32>,
+        "__embind_register_memory_view" => embind::__embind_register_memory_view::<Memory32>,
+        "__embind_register_std_string" => embind::__embind_register_std_string::<Memory32>,
+        "__embind_register_std_wstring" => embind::__embind_register_std_wstring::<Memory32>,
+        "__embind_register_value_object" => value_object::__embind_register_value_object::<Memory32>,
+        "__embind_register_value_object_field" => value_object::__embind_register_value_object_field::<Memory32>,
+        "__embind_register_void" => embind::__embind_register_void::<Memory32>,
+        "__emscripten_date_now" => time::__emscripten_date_now::<Memory32>,
+        "__emscripten_get_now_is_monotonic" => time::__emscripten_get_now_is_monotonic::<Memory32>,
+        "__emval_call" => emval::__emval_call::<Memory32>,
+        "__emval_decref" => emval::__emval_decref::<Memory32>,
+        "__emval_incref" => emval::__emval_incref::<Memory32>,
+        "__emval_take_value" => emval::__emval_take_value::<Memory32>,
+        "__gmtime_js" => time::__gmtime_js::<Memory32>,
+        "__localtime_js" => time::__localtime_js::<Memory32>,
+        "__mktime_js" => time::__mktime_js::<Memory32>,
+        "__tzset_js" => time::__tzset_js::<Memory32>,
+        "_abort" => trap::_abort::<Memory32>,
+        "_emscripten_get_now" => time::_emscripten_get_now::<Memory32>,
+        "_emscripten_memcpy_big" => heap::_emscripten_memcpy_big::<Memory32>,
+        "_emscripten_resize_heap" => heap::_emscripten_resize_heap::<Memory32>,
+        "_environ_get" => environ::_environ_get::<Memory32>,
+        "_environ_sizes_get" => environ::_environ_sizes_get::<Memory32>,
+        "_fd_close" => syscalls::_fd_close::<Memory32>,
+        "_fd_read" => syscalls::_fd_read::<Memory32>,
+        "_fd_seek" => syscalls::_fd_seek::<Memory32>,
+        "_fd_write" => syscalls::_fd_write::<Memory32>,
+        "_setTempRet0" => invoke::_setTempRet0::<Memory32>,
+        "_strftime" => time::_strftime::<Memory32>,
+        "_strftime_l" => time::_strftime_l::<Memory32>,
+    }
+    functions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `TESSERACT_WASM` and `TESSERACT_GLUE` have to come from the same
+    /// build, otherwise no engine can be created
+    #[test]
+    fn bundled_module_links() {
+        let vm = TesseractVm::new().unwrap();
+        vm.engine().unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_environment() {
+        let empty_module = b"\0asm\x01\0\0\0";
+        let vm = || TesseractVm::with_module(empty_module, ImportManifest::default()).unwrap();
+        assert!(matches!(vm().env("A=B", "1"), Err(OcrError::InvalidOptions(_))));
+        assert!(matches!(vm().env("LANG", "C\0"), Err(OcrError::InvalidOptions(_))));
+        let vm = vm().env("LANG", "de_DE.UTF-8").unwrap().remove_env("HOME");
+        assert_eq!(vm.environ.get("LANG"), Some("de_DE.UTF-8"));
+        assert_eq!(vm.environ.get("HOME"), None);
+    }
+}
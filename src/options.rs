@@ -0,0 +1,359 @@
+//! Settings of a `TesseractVm::ocr_image_with` run
+//!
+//! `OcrOptions` is turned into the tesseract variables set on the engine
+//! before the image is recognized, and into the models mounted under
+//! `TESSDATA_DIR`. Everything is checked on the host first, so that a typo
+//! fails with `OcrError::InvalidOptions` instead of a guest abort halfway
+//! through the page.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use bitflags::bitflags;
+use crate::error::OcrError;
+use crate::interrupt::CancellationToken;
+
+/// Page segmentation mode, `tessedit_pageseg_mode`
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum PageSegMode {
+    /// Orientation and script detection only
+    OsdOnly,
+    /// Automatic page segmentation with OSD
+    AutoOsd,
+    /// Automatic page segmentation, but no OSD or OCR
+    AutoOnly,
+    /// Fully automatic page segmentation, but no OSD (tesseract's default)
+    #[default]
+    Auto,
+    /// A single column of text of variable sizes
+    SingleColumn,
+    /// A single uniform block of vertically aligned text
+    SingleBlockVertText,
+    /// A single uniform block of text
+    SingleBlock,
+    /// A single text line
+    SingleLine,
+    /// A single word
+    SingleWord,
+    /// A single word in a circle
+    CircleWord,
+    /// A single character
+    SingleChar,
+    /// As much text as possible in no particular order
+    SparseText,
+    /// Sparse text with OSD
+    SparseTextOsd,
+    /// A single text line, bypassing tesseract-specific hacks
+    RawLine,
+}
+
+impl PageSegMode {
+    /// The value of `tessedit_pageseg_mode`
+    pub fn value(self) -> u32 {
+        match self {
+            PageSegMode::OsdOnly => 0,
+            PageSegMode::AutoOsd => 1,
+            PageSegMode::AutoOnly => 2,
+            PageSegMode::Auto => 3,
+            PageSegMode::SingleColumn => 4,
+            PageSegMode::SingleBlockVertText => 5,
+            PageSegMode::SingleBlock => 6,
+            PageSegMode::SingleLine => 7,
+            PageSegMode::SingleWord => 8,
+            PageSegMode::CircleWord => 9,
+            PageSegMode::SingleChar => 10,
+            PageSegMode::SparseText => 11,
+            PageSegMode::SparseTextOsd => 12,
+            PageSegMode::RawLine => 13,
+        }
+    }
+
+    /// Whether the mode runs orientation and script detection, which
+    /// needs `osd.traineddata`
+    pub fn uses_osd(self) -> bool {
+        matches!(self, PageSegMode::OsdOnly | PageSegMode::AutoOsd | PageSegMode::SparseTextOsd)
+    }
+}
+
+bitflags! {
+    /// Results requested from a run, one field of `OcrOutput` each
+    ///
+    /// There is no PDF: `OCREngine` has no renderers, and a PDF with a
+    /// text layer would have to be written on the host from scratch.
+    pub struct OutputFormats: u32 {
+        const HOCR = 1;
+        const TEXT = 2;
+        const TSV = 4;
+    }
+}
+
+/// Where the models of a run are mounted in the guest filesystem,
+/// `TESSDATA_PREFIX` of the guest
+pub const TESSDATA_DIR: &str = "/tessdata";
+
+/// Variables set from fields of `OcrOptions`, not through `variables`
+const RESERVED_VARIABLES: [&str; 4] = [
+    "tessedit_pageseg_mode",
+    "user_defined_dpi",
+    "tessedit_char_whitelist",
+    "tessedit_char_blacklist",
+];
+
+/// Settings of one `TesseractVm::ocr_image_with` run
+///
+/// The default recognizes English with tesseract's default segmentation
+/// and produces hOCR. There is no engine mode: `OCREngine.loadModel` always
+/// runs the LSTM recognizer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OcrOptions {
+    pub psm: PageSegMode,
+    /// the models to load, e.g. `["eng", "deu"]` for `-l eng+deu`; script
+    /// models are named like their file in `tessdata/`, e.g. `script/Latin`
+    pub languages: Vec<String>,
+    /// resolution of the image, `user_defined_dpi`; estimated if not set
+    pub dpi: Option<u32>,
+    /// `tessedit_char_whitelist`
+    pub whitelist: Option<String>,
+    /// `tessedit_char_blacklist`
+    pub blacklist: Option<String>,
+    /// further variables, see `tesseract --print-parameters`
+    pub variables: BTreeMap<String, String>,
+    pub outputs: OutputFormats,
+    /// limit of the whole run
+    pub timeout: Option<Duration>,
+    /// stops the run when cancelled
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl Default for OcrOptions {
+    fn default() -> Self {
+        Self {
+            psm: PageSegMode::default(),
+            languages: vec!["eng".to_string()],
+            dpi: None,
+            whitelist: None,
+            blacklist: None,
+            variables: BTreeMap::new(),
+            outputs: OutputFormats::HOCR,
+            timeout: None,
+            cancellation: None,
+        }
+    }
+}
+
+impl OcrOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn psm(mut self, psm: PageSegMode) -> Self {
+        self.psm = psm;
+        self
+    }
+
+    /// Replaces the languages, e.g. `["eng", "deu", "fra"]` or `["script/Latin"]`
+    pub fn languages<S: Into<String>>(mut self, languages: impl IntoIterator<Item = S>) -> Self {
+        self.languages = languages.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn dpi(mut self, dpi: u32) -> Self {
+        self.dpi = Some(dpi);
+        self
+    }
+
+    /// Only recognize these characters
+    pub fn whitelist(mut self, chars: &str) -> Self {
+        self.whitelist = Some(chars.to_string());
+        self
+    }
+
+    /// Never recognize these characters
+    pub fn blacklist(mut self, chars: &str) -> Self {
+        self.blacklist = Some(chars.to_string());
+        self
+    }
+
+    /// Sets a tesseract variable, like `-c name=value` of the CLI
+    pub fn variable(mut self, name: &str, value: &str) -> Self {
+        self.variables.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn outputs(mut self, outputs: OutputFormats) -> Self {
+        self.outputs = outputs;
+        self
+    }
+
+    /// Fails the run with `OcrError::Timeout` if it takes longer than `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fails the run with `OcrError::Cancelled` once `token` is cancelled
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Checks the options for mistakes that would only show up once the
+    /// guest runs
+    pub fn validate(&self) -> Result<(), OcrError> {
+        let invalid = |e: String| Err(OcrError::InvalidOptions(e));
+        if self.languages.is_empty() {
+            return invalid("no language given".to_string());
+        }
+        for (i, language) in self.languages.iter().enumerate() {
+            let name = language.strip_prefix("script/").unwrap_or(language);
+            let valid = !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return invalid(format!("invalid language name {language:?}"));
+            }
+            if self.languages[..i].contains(language) {
+                return invalid(format!("language {language} is given twice"));
+            }
+        }
+        if let Some(dpi) = self.dpi {
+            if !(70..=2400).contains(&dpi) {
+                return invalid(format!("dpi {dpi} is outside of 70 - 2400"));
+            }
+        }
+        for (name, value) in &self.variables {
+            if name.is_empty() || name.contains(|c: char| c == '=' || c.is_whitespace()) {
+                return invalid(format!("invalid variable name {name:?}"));
+            }
+            if value.contains('\n') {
+                return invalid(format!("value of {name} contains a line break"));
+            }
+            if RESERVED_VARIABLES.contains(&name.as_str()) {
+                return invalid(format!("{name} is set through OcrOptions, not as a variable"));
+            }
+        }
+        if self.outputs.is_empty() {
+            return invalid("no output format selected".to_string());
+        }
+        Ok(())
+    }
+
+    /// The `-l` argument of the CLI, e.g. `eng+deu`
+    pub fn language(&self) -> String {
+        self.languages.join("+")
+    }
+
+    /// Every model the run loads: the languages in order, then `osd` for
+    /// the page segmentation modes that detect the orientation
+    pub fn required_models(&self) -> Vec<String> {
+        let mut models = self.languages.clone();
+        if self.psm.uses_osd() && !models.iter().any(|m| m == "osd") {
+            models.push("osd".to_string());
+        }
+        models
+    }
+
+    /// All tesseract variables of the run, in the order they are set
+    pub fn tesseract_variables(&self) -> Vec<(String, String)> {
+        let mut variables = vec![
+            ("tessedit_pageseg_mode".to_string(), self.psm.value().to_string()),
+        ];
+        if let Some(dpi) = self.dpi {
+            variables.push(("user_defined_dpi".to_string(), dpi.to_string()));
+        }
+        if let Some(whitelist) = &self.whitelist {
+            variables.push(("tessedit_char_whitelist".to_string(), whitelist.clone()));
+        }
+        if let Some(blacklist) = &self.blacklist {
+            variables.push(("tessedit_char_blacklist".to_string(), blacklist.clone()));
+        }
+        variables.extend(self.variables.iter().map(|(name, value)| (name.clone(), value.clone())));
+        variables
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(options: OcrOptions) -> String {
+        match options.validate() {
+            Err(OcrError::InvalidOptions(e)) => e,
+            other => panic!("expected InvalidOptions, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn default_is_valid() {
+        OcrOptions::default().validate().unwrap();
+        OcrOptions::new().languages(["script/Latin"]).psm(PageSegMode::SingleLine).validate().unwrap();
+        OcrOptions::new().languages(["eng", "deu", "fra"]).validate().unwrap();
+        assert_eq!(OcrOptions::new().languages(["eng", "deu", "fra"]).language(), "eng+deu+fra");
+    }
+
+    #[test]
+    fn required_models() {
+        let options = OcrOptions::new().languages(["eng", "script/Latin"]);
+        assert_eq!(options.required_models(), ["eng", "script/Latin"]);
+        for psm in [PageSegMode::OsdOnly, PageSegMode::AutoOsd, PageSegMode::SparseTextOsd] {
+            let options = options.clone().psm(psm);
+            options.validate().unwrap();
+            assert_eq!(options.required_models(), ["eng", "script/Latin", "osd"]);
+        }
+        let options = OcrOptions::new().languages(["osd"]).psm(PageSegMode::OsdOnly);
+        assert_eq!(options.required_models(), ["osd"]);
+    }
+
+    #[test]
+    fn rejects_languages() {
+        assert!(rejected(OcrOptions::new().languages(Vec::<String>::new())).contains("no language"));
+        assert!(rejected(OcrOptions::new().languages(["eng", "deu", "eng"])).contains("twice"));
+        for name in ["", "../eng", "eng deu", "script/", "script/../eng"] {
+            assert!(rejected(OcrOptions::new().languages([name])).contains("invalid language"), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_dpi_and_outputs() {
+        OcrOptions::new().dpi(70).validate().unwrap();
+        OcrOptions::new().dpi(2400).validate().unwrap();
+        assert!(rejected(OcrOptions::new().dpi(69)).contains("dpi 69"));
+        assert!(rejected(OcrOptions::new().dpi(2401)).contains("dpi 2401"));
+        assert!(rejected(OcrOptions::new().outputs(OutputFormats::empty())).contains("no output"));
+    }
+
+    #[test]
+    fn rejects_variables() {
+        assert!(rejected(OcrOptions::new().variable("", "1")).contains("invalid variable"));
+        assert!(rejected(OcrOptions::new().variable("a=b", "1")).contains("invalid variable"));
+        assert!(rejected(OcrOptions::new().variable("a b", "1")).contains("invalid variable"));
+        assert!(rejected(OcrOptions::new().variable("debug_file", "a\nb")).contains("line break"));
+        for name in RESERVED_VARIABLES {
+            assert!(rejected(OcrOptions::new().variable(name, "1")).contains("OcrOptions"));
+        }
+    }
+
+    #[test]
+    fn variable_order() {
+        let options = OcrOptions::new()
+            .psm(PageSegMode::SingleBlock)
+            .variable("preserve_interword_spaces", "1")
+            .blacklist("|")
+            .whitelist("0123456789")
+            .dpi(300)
+            .variable("classify_bln_numeric_mode", "1");
+        let variables = options.tesseract_variables();
+        let names: Vec<_> = variables.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, [
+            "tessedit_pageseg_mode",
+            "user_defined_dpi",
+            "tessedit_char_whitelist",
+            "tessedit_char_blacklist",
+            "classify_bln_numeric_mode",
+            "preserve_interword_spaces",
+        ]);
+        assert_eq!(variables[0].1, "6");
+        assert_eq!(variables[1].1, "300");
+        assert_eq!(OcrOptions::default().tesseract_variables(), [
+            ("tessedit_pageseg_mode".to_string(), "3".to_string()),
+        ]);
+    }
+}
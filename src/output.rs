@@ -0,0 +1,207 @@
+//! Results of a `TesseractVm::ocr_image_with` run
+//!
+//! The text comes from the engine as is. hOCR and TSV are approximations
+//! rendered on the host from the word boxes, since `OCREngine` has no
+//! renderers. They follow the layout of tesseract's own `hocr` and `tsv`
+//! output, but lines are found from the layout flags of the words, and
+//! there are no blocks, paragraphs, baselines or font sizes: every line is
+//! put into block 1, paragraph 1.
+
+use std::fmt::Write;
+use crate::engine::{IntRect, LayoutFlags, TextItem};
+
+/// What the guest printed to its standard streams
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuestOutput {
+    pub stdout: String,
+    /// warnings like `Estimating resolution as 300`
+    pub stderr: String,
+}
+
+/// Everything a run produced, `None` for formats that were not requested
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OcrOutput {
+    /// `OutputFormats::HOCR`, see `hocr_from_words`
+    pub hocr: Option<String>,
+    /// `OutputFormats::TEXT`
+    pub text: Option<String>,
+    /// `OutputFormats::TSV`, see `tsv_from_words`
+    pub tsv: Option<String>,
+    /// stdout and stderr of the run
+    pub console: GuestOutput,
+}
+
+/// Groups words into text lines by their layout flags, with the box
+/// around each line
+fn lines(words: &[TextItem]) -> Vec<(IntRect, &[TextItem])> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, word) in words.iter().enumerate() {
+        let last = i + 1 == words.len()
+            || word.flags.contains(LayoutFlags::END_OF_LINE)
+            || words[i + 1].flags.contains(LayoutFlags::START_OF_LINE);
+        if last {
+            let line = &words[start..=i];
+            let rect = line.iter().skip(1).fold(line[0].rect, |r, w| IntRect {
+                left: r.left.min(w.rect.left),
+                top: r.top.min(w.rect.top),
+                right: r.right.max(w.rect.right),
+                bottom: r.bottom.max(w.rect.bottom),
+            });
+            lines.push((rect, line));
+            start = i + 1;
+        }
+    }
+    lines
+}
+
+fn bbox(rect: &IntRect) -> String {
+    format!("bbox {} {} {} {}", rect.left, rect.top, rect.right, rect.bottom)
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Approximate hOCR document of one page of `width` x `height` pixels,
+/// with an `ocr_line` for each line of `words` and no `ocr_carea` or
+/// `ocr_par`
+pub fn hocr_from_words(width: u32, height: u32, words: &[TextItem]) -> String {
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Transitional//EN\"\n",
+        "    \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd\">\n",
+        "<html xmlns=\"http://www.w3.org/1999/xhtml\" xml:lang=\"en\" lang=\"en\">\n",
+        " <head>\n",
+        "  <title></title>\n",
+        "  <meta http-equiv=\"Content-Type\" content=\"text/html;charset=utf-8\"/>\n",
+        "  <meta name='ocr-system' content='tesseract'/>\n",
+        "  <meta name='ocr-capabilities' content='ocr_page ocr_line ocrx_word'/>\n",
+        " </head>\n",
+        " <body>\n",
+    ));
+    let page = IntRect { left: 0, top: 0, right: width as i32, bottom: height as i32 };
+    let _ = writeln!(out, "  <div class='ocr_page' id='page_1' title='{}'>", bbox(&page));
+    let mut word_id = 0;
+    for (line_id, (rect, line)) in lines(words).into_iter().enumerate() {
+        let _ = writeln!(out, "   <span class='ocr_line' id='line_1_{}' title='{}'>", line_id + 1, bbox(&rect));
+        for word in line {
+            word_id += 1;
+            let _ = writeln!(
+                out,
+                "    <span class='ocrx_word' id='word_1_{word_id}' title='{}; x_wconf {}'>{}</span>",
+                bbox(&word.rect),
+                (word.confidence * 100.0).round() as i32,
+                escape_xml(&word.text),
+            );
+        }
+        out.push_str("   </span>\n");
+    }
+    out.push_str("  </div>\n </body>\n</html>\n");
+    out
+}
+
+/// Approximate `tesseract ... tsv` output: rows of the page (level 1),
+/// its lines (level 4) and their words (level 5), without the block and
+/// paragraph rows
+pub fn tsv_from_words(width: u32, height: u32, words: &[TextItem]) -> String {
+    let mut out = String::from("level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext\n");
+    let _ = writeln!(out, "1\t1\t0\t0\t0\t0\t0\t0\t{width}\t{height}\t-1\t");
+    for (line_num, (rect, line)) in lines(words).into_iter().enumerate() {
+        let line_num = line_num + 1;
+        let _ = writeln!(
+            out,
+            "4\t1\t1\t1\t{line_num}\t0\t{}\t{}\t{}\t{}\t-1\t",
+            rect.left, rect.top, rect.right - rect.left, rect.bottom - rect.top,
+        );
+        for (word_num, word) in line.iter().enumerate() {
+            let r = &word.rect;
+            let _ = writeln!(
+                out,
+                "5\t1\t1\t1\t{line_num}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{}",
+                word_num + 1, r.left, r.top, r.right - r.left, r.bottom - r.top,
+                word.confidence * 100.0,
+                word.text.replace(['\t', '\n'], " "),
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(left: i32, right: i32, flags: LayoutFlags, text: &str) -> TextItem {
+        TextItem {
+            rect: IntRect { left, top: 10, right, bottom: 20 },
+            flags,
+            confidence: 0.5,
+            text: text.to_string(),
+        }
+    }
+
+    fn page() -> Vec<TextItem> {
+        vec![
+            word(0, 10, LayoutFlags::START_OF_LINE, "a<b"),
+            word(12, 30, LayoutFlags::END_OF_LINE, "c"),
+            word(5, 15, LayoutFlags::START_OF_LINE | LayoutFlags::END_OF_LINE, "d\te"),
+        ]
+    }
+
+    #[test]
+    fn groups_lines() {
+        let words = page();
+        let found = lines(&words);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].0, IntRect { left: 0, top: 10, right: 30, bottom: 20 });
+        assert_eq!(found[0].1.len(), 2);
+        assert_eq!(found[1].0, words[2].rect);
+
+        // a new line starts without the previous one being ended
+        let words = [word(0, 10, LayoutFlags::empty(), "a"), word(0, 10, LayoutFlags::START_OF_LINE, "b")];
+        assert_eq!(lines(&words).len(), 2);
+        assert!(lines(&[]).is_empty());
+    }
+
+    #[test]
+    fn renders_hocr() {
+        let hocr = hocr_from_words(40, 30, &page());
+        assert!(hocr.contains("<div class='ocr_page' id='page_1' title='bbox 0 0 40 30'>"));
+        assert!(hocr.contains("<span class='ocr_line' id='line_1_1' title='bbox 0 10 30 20'>"));
+        assert!(hocr.contains("<span class='ocr_line' id='line_1_2' title='bbox 5 10 15 20'>"));
+        assert!(hocr.contains("<span class='ocrx_word' id='word_1_1' title='bbox 0 10 10 20; x_wconf 50'>a&lt;b</span>"));
+        assert!(hocr.contains("<span class='ocrx_word' id='word_1_3' title='bbox 5 10 15 20; x_wconf 50'>d\te</span>"));
+        assert_eq!(hocr.matches("<span").count(), 5);
+        assert_eq!(hocr.matches("</span>").count(), 5);
+        assert!(hocr.ends_with("</html>\n"));
+
+        let empty = hocr_from_words(40, 30, &[]);
+        assert!(empty.contains("title='bbox 0 0 40 30'>\n  </div>\n"));
+    }
+
+    #[test]
+    fn renders_tsv() {
+        let tsv = tsv_from_words(40, 30, &page());
+        let rows: Vec<_> = tsv.lines().collect();
+        assert_eq!(rows.len(), 7);
+        assert!(rows.iter().all(|row| row.split('\t').count() == 12));
+        assert_eq!(rows[1], "1\t1\t0\t0\t0\t0\t0\t0\t40\t30\t-1\t");
+        assert_eq!(rows[2], "4\t1\t1\t1\t1\t0\t0\t10\t30\t10\t-1\t");
+        assert_eq!(rows[3], "5\t1\t1\t1\t1\t1\t0\t10\t10\t10\t50.000000\ta<b");
+        assert_eq!(rows[4], "5\t1\t1\t1\t1\t2\t12\t10\t18\t10\t50.000000\tc");
+        assert_eq!(rows[5], "4\t1\t1\t1\t2\t0\t5\t10\t10\t10\t-1\t");
+        assert_eq!(rows[6], "5\t1\t1\t1\t2\t1\t5\t10\t10\t10\t50.000000\td e");
+    }
+}
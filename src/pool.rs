@@ -0,0 +1,322 @@
+//! A fixed number of ready-to-use engines shared between threads
+//!
+//! Instantiating the module and parsing a `.traineddata` takes far longer
+//! than recognizing a typical page, so `OcrPool` does both up front and
+//! lends the engines out one image at a time. Callers that find every
+//! engine busy wait in a bounded queue; once the queue is full, `get`
+//! fails with `OcrError::QueueFull` instead of piling up work.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use crate::TesseractVm;
+use crate::engine::OcrEngine;
+use crate::error::OcrError;
+use crate::snapshot::EngineSnapshot;
+
+/// Default of `OcrPool::with_queue_limit`: waiting callers per engine
+const QUEUE_PER_ENGINE: usize = 4;
+
+/// `size` engines with the same model loaded
+///
+/// The pool is `Sync`, share it between threads with an `Arc` or
+/// `std::thread::scope`. Engines that trapped, ran out of memory, timed
+/// out or were cancelled are dropped when they are returned, the next
+/// `get` that finds no idle engine creates a fresh one in their place.
+/// All but the first engine start from an `EngineSnapshot` of the first
+/// one.
+pub struct OcrPool {
+    vm: TesseractVm,
+    model: Vec<u8>,
+    /// the first engine right after loading the model
+    snapshot: Option<EngineSnapshot>,
+    size: usize,
+    queue_limit: usize,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+struct PoolState<E = OcrEngine> {
+    idle: Vec<E>,
+    /// idle and lent out, less than `size` while a replacement is pending
+    live: usize,
+    waiting: usize,
+}
+
+/// What a caller of `get` does next, see `PoolState::admit`
+#[derive(Debug, PartialEq, Eq)]
+enum Admission<E> {
+    /// borrow this idle engine
+    Idle(E),
+    /// create the replacement of a discarded engine, already counted as live
+    Replace,
+    /// wait for an engine to be returned, already counted as waiting
+    Wait,
+    /// all engines are busy and the queue is full
+    Full,
+}
+
+impl<E> Default for PoolState<E> {
+    fn default() -> Self {
+        Self { idle: Vec::new(), live: 0, waiting: 0 }
+    }
+}
+
+impl<E> PoolState<E> {
+    fn admit(&mut self, size: usize, queue_limit: usize) -> Admission<E> {
+        if let Some(engine) = self.idle.pop() {
+            return Admission::Idle(engine);
+        }
+        if self.live < size {
+            self.live += 1;
+            return Admission::Replace;
+        }
+        if self.waiting >= queue_limit {
+            return Admission::Full;
+        }
+        self.waiting += 1;
+        Admission::Wait
+    }
+
+    /// Takes back a returned engine, `None` if it was discarded
+    fn put_back(&mut self, engine: Option<E>) {
+        match engine {
+            Some(engine) => self.idle.push(engine),
+            // `get` creates it once someone needs it
+            None => self.live -= 1,
+        }
+    }
+}
+
+impl fmt::Debug for OcrPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("OcrPool")
+            .field("vm", &self.vm)
+            .field("size", &self.size)
+            .field("queue_limit", &self.queue_limit)
+            .field("idle", &state.idle.len())
+            .field("live", &state.live)
+            .field("waiting", &state.waiting)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OcrPool {
+    /// Creates `size` engines and loads `model` (e.g. `eng`) from the
+    /// tessdata source of `vm` into each of them
+    pub fn new(vm: &TesseractVm, model: &str, size: usize) -> Result<Self, OcrError> {
+        if size == 0 {
+            return Err(OcrError::InvalidOptions("pool size is zero".to_string()));
+        }
+        let data = vm.tessdata.load(model)?
+            .ok_or_else(|| OcrError::MissingTrainedData { languages: vec![model.to_string()] })?
+            .into_owned();
+        let mut pool = Self {
+            vm: vm.clone(),
+            model: data,
+            snapshot: None,
+            size,
+            queue_limit: size * QUEUE_PER_ENGINE,
+            state: Mutex::new(PoolState::default()),
+            returned: Condvar::new(),
+        };
+        let mut first = pool.new_engine()?;
+        // without a snapshot every engine loads the model itself
+        pool.snapshot = first.snapshot().ok();
+        let engines = std::iter::once(Ok(first))
+            .chain((1..size).map(|_| pool.new_engine()))
+            .collect::<Result<Vec<_>, _>>()?;
+        {
+            let mut state = pool.lock();
+            state.live = engines.len();
+            state.idle = engines;
+        }
+        Ok(pool)
+    }
+
+    /// Limits how many callers may wait for an engine at the same time,
+    /// `4 * size` by default, 0 makes `get` fail whenever all are busy
+    pub fn with_queue_limit(mut self, limit: usize) -> Self {
+        self.queue_limit = limit;
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Borrows an engine, waiting for one to be returned if all are busy
+    ///
+    /// The engine has the model loaded and no image. Variables set with
+    /// `OcrEngine::set_variable` stay set for the next borrower.
+    pub fn get(&self) -> Result<PooledEngine<'_>, OcrError> {
+        let mut state = self.lock();
+        loop {
+            match state.admit(self.size, self.queue_limit) {
+                Admission::Idle(engine) => return Ok(PooledEngine { pool: self, engine: Some(engine) }),
+                Admission::Replace => return self.replace(state),
+                Admission::Full => return Err(OcrError::QueueFull { limit: self.queue_limit }),
+                Admission::Wait => {
+                    state = self.returned.wait(state).unwrap_or_else(|e| e.into_inner());
+                    state.waiting -= 1;
+                },
+            }
+        }
+    }
+
+    /// Borrows an engine if one is idle right now, or creates the
+    /// replacement of a discarded one; `None` if all are busy
+    pub fn try_get(&self) -> Result<Option<PooledEngine<'_>>, OcrError> {
+        let mut state = self.lock();
+        // a queue of zero never waits
+        match state.admit(self.size, 0) {
+            Admission::Idle(engine) => Ok(Some(PooledEngine { pool: self, engine: Some(engine) })),
+            Admission::Replace => self.replace(state).map(Some),
+            Admission::Wait | Admission::Full => Ok(None),
+        }
+    }
+
+    /// Creates an engine in place of one that was discarded in `put_back`,
+    /// `admit` already counted it as live
+    fn replace(&self, state: MutexGuard<'_, PoolState>) -> Result<PooledEngine<'_>, OcrError> {
+        drop(state);
+        match self.new_engine() {
+            Ok(engine) => Ok(PooledEngine { pool: self, engine: Some(engine) }),
+            Err(e) => {
+                self.lock().put_back(None);
+                self.returned.notify_one();
+                Err(e)
+            },
+        }
+    }
+
+    /// Recognizes the text of an RGBA image with the next free engine
+    pub fn get_text(&self, width: u32, height: u32, rgba: &[u8]) -> Result<String, OcrError> {
+        let mut engine = self.get()?;
+        engine.load_image(width, height, rgba)?;
+        engine.get_text()
+    }
+
+    fn new_engine(&self) -> Result<OcrEngine, OcrError> {
+        if let Some(snapshot) = &self.snapshot {
+            return OcrEngine::from_snapshot(&self.vm, snapshot);
+        }
+        let mut engine = self.vm.engine()?;
+        engine.load_model(&self.model)?;
+        Ok(engine)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        // the state is consistent between statements, a panicking
+        // borrower can't leave it half-updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Takes back an engine, dropping it if it is no longer usable
+    ///
+    /// This runs when a `PooledEngine` is dropped, so it leaves creating
+    /// the replacement to the next caller of `get`.
+    fn put_back(&self, mut engine: OcrEngine) {
+        // limits of the last borrower
+        engine.set_timeout(None);
+        engine.set_cancellation_token(None);
+        engine.set_progress_channel(None);
+        engine.take_output();
+        let engine = (engine.is_usable() && engine.clear_image().is_ok()).then_some(engine);
+        self.lock().put_back(engine);
+        self.returned.notify_one();
+    }
+}
+
+/// An engine borrowed from an `OcrPool`, returned when dropped
+pub struct PooledEngine<'a> {
+    pool: &'a OcrPool,
+    engine: Option<OcrEngine>,
+}
+
+impl Deref for PooledEngine<'_> {
+    type Target = OcrEngine;
+
+    fn deref(&self) -> &OcrEngine {
+        self.engine.as_ref().expect("engine taken before drop")
+    }
+}
+
+impl DerefMut for PooledEngine<'_> {
+    fn deref_mut(&mut self) -> &mut OcrEngine {
+        self.engine.as_mut().expect("engine taken before drop")
+    }
+}
+
+impl Drop for PooledEngine<'_> {
+    fn drop(&mut self) {
+        if let Some(engine) = self.engine.take() {
+            self.pool.put_back(engine);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(idle: Vec<u32>, live: usize) -> PoolState<u32> {
+        PoolState { idle, live, waiting: 0 }
+    }
+
+    #[test]
+    fn lends_idle_engines() {
+        let mut state = state(vec![1, 2], 2);
+        assert_eq!(state.admit(2, 0), Admission::Idle(2));
+        assert_eq!(state.admit(2, 0), Admission::Idle(1));
+        assert_eq!(state.admit(2, 0), Admission::Full);
+        state.put_back(Some(1));
+        assert_eq!(state.admit(2, 0), Admission::Idle(1));
+        assert_eq!(state.live, 2);
+    }
+
+    #[test]
+    fn queue_full_at_limit() {
+        let mut state = state(Vec::new(), 2);
+        for waiting in 1..=3 {
+            assert_eq!(state.admit(2, 3), Admission::Wait);
+            assert_eq!(state.waiting, waiting);
+        }
+        assert_eq!(state.admit(2, 3), Admission::Full);
+        assert_eq!(state.waiting, 3);
+        // a woken caller leaves the queue before trying again
+        state.waiting -= 1;
+        state.put_back(Some(7));
+        assert_eq!(state.admit(2, 3), Admission::Idle(7));
+        assert_eq!(state.admit(2, 3), Admission::Wait);
+        assert_eq!(state.admit(2, 3), Admission::Full);
+    }
+
+    #[test]
+    fn replaces_discarded_engines() {
+        let mut state = state(Vec::new(), 2);
+        state.put_back(None);
+        assert_eq!(state.live, 1);
+        assert_eq!(state.admit(2, 0), Admission::Replace);
+        assert_eq!(state.live, 2);
+        assert_eq!(state.admit(2, 0), Admission::Full);
+        // the replacement failed
+        state.put_back(None);
+        assert_eq!(state.admit(2, 0), Admission::Replace);
+        assert_eq!(state.waiting, 0);
+    }
+
+    #[test]
+    fn waiters_replace_discarded_engines() {
+        let mut state = state(Vec::new(), 1);
+        assert_eq!(state.admit(1, 1), Admission::Wait);
+        // the borrower's engine was discarded, the woken caller creates
+        // the replacement
+        state.put_back(None);
+        state.waiting -= 1;
+        assert_eq!(state.admit(1, 1), Admission::Replace);
+        assert_eq!(state.live, 1);
+        assert!(state.idle.is_empty());
+    }
+}
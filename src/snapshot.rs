@@ -0,0 +1,303 @@
+//! Engines restored from a copy of an initialized instance
+//!
+//! Static constructors, embind registration and parsing a `.traineddata`
+//! take most of the time of a fresh `OcrEngine`. An `EngineSnapshot`
+//! records the result instead: the pages of the linear memory that are not
+//! all zero, every mutable global and the host state built up by the
+//! imports. A new instance gets a copy of the pages and never runs the
+//! constructors.
+//!
+//! Globals like the stack pointer are internal to the module, so every
+//! module is compiled with `ExportGlobals`, which exports each mutable
+//! global under `GLOBAL_EXPORT_PREFIX` and its index.
+
+use std::fmt;
+use std::sync::Arc;
+use wasmer::{
+    CompilerConfig, Extern, FunctionEnv, FunctionMiddleware, Instance, LocalFunctionIndex,
+    Memory, MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Mutability, Pages, Store, Value,
+};
+use wasmer::wasmparser::Operator;
+use wasmer_types::{ExportIndex, ModuleInfo};
+use crate::class::ClassRegistry;
+use crate::embind::TypeRegistry;
+use crate::emval::{HandleTable, HandleValues};
+use crate::env::EmscriptenEnv;
+use crate::error::OcrError;
+use crate::heap::PAGE_SIZE;
+
+/// Name prefix of the exports `ExportGlobals` adds
+pub const GLOBAL_EXPORT_PREFIX: &str = "__snapshot_global_";
+
+/// Page index and contents of every page that is not all zero
+type MemoryImage = Vec<(u64, Box<[u8]>)>;
+
+/// Adds the `ExportGlobals` middleware snapshots rely on to `compiler`
+pub fn instrument(compiler: &mut impl CompilerConfig) {
+    compiler.push_middleware(Arc::new(ExportGlobals));
+}
+
+/// Exports every mutable global of a module, including the ones added
+/// by earlier middlewares, so that `EngineSnapshot` can read and set them
+#[derive(Debug)]
+pub struct ExportGlobals;
+
+impl ModuleMiddleware for ExportGlobals {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        Box::new(ExportGlobals)
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let mutable = module_info.globals.iter()
+            .filter(|(_, ty)| ty.mutability == Mutability::Var)
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        for index in mutable {
+            let name = format!("{GLOBAL_EXPORT_PREFIX}{}", index.as_u32());
+            module_info.exports.insert(name, ExportIndex::Global(index));
+        }
+    }
+}
+
+impl FunctionMiddleware for ExportGlobals {
+    fn feed<'a>(&mut self, operator: Operator<'a>, state: &mut MiddlewareReaderState<'a>) -> Result<(), MiddlewareError> {
+        state.push_operator(operator);
+        Ok(())
+    }
+}
+
+/// State of an `OcrEngine` between two calls, see `OcrEngine::snapshot`
+///
+/// A snapshot only fits instances of the module it was taken from.
+#[derive(Clone)]
+pub struct EngineSnapshot {
+    memory_size: u64,
+    /// shared by all clones, the image never changes
+    memory: Arc<MemoryImage>,
+    /// all mutable globals, by the name `ExportGlobals` exports them as
+    globals: Vec<(String, Value)>,
+    registry: TypeRegistry,
+    classes: ClassRegistry,
+    emval: HandleValues,
+    /// `__tzset_js` ran, the clock stays the one of the restoring VM
+    tzset_called: bool,
+    /// `OCREngine*` on the guest heap
+    pub(crate) engine: u32,
+    pub(crate) model_loaded: bool,
+    pub(crate) image_loaded: bool,
+}
+
+impl fmt::Debug for EngineSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EngineSnapshot")
+            .field("memory_size", &self.memory_size)
+            .field("pages", &self.memory.len())
+            .field("globals", &self.globals)
+            .field("engine", &self.engine)
+            .field("model_loaded", &self.model_loaded)
+            .field("image_loaded", &self.image_loaded)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EngineSnapshot {
+    /// Copies the state of `instance`, which must not be inside a call
+    pub(crate) fn capture(
+        store: &mut Store,
+        instance: &Instance,
+        env: &FunctionEnv<EmscriptenEnv>,
+        engine: u32,
+        model_loaded: bool,
+        image_loaded: bool,
+    ) -> Result<Self, OcrError> {
+        let mut globals = Vec::new();
+        for (name, export) in instance.exports.iter() {
+            let global = match export {
+                Extern::Global(global) if name.starts_with(GLOBAL_EXPORT_PREFIX) => global,
+                _ => continue,
+            };
+            match global.get(&mut *store) {
+                value @ (Value::I32(_) | Value::I64(_) | Value::F32(_) | Value::F64(_) | Value::V128(_)) => {
+                    globals.push((name.clone(), value));
+                },
+                _ => return Err(OcrError::Other(format!("snapshot: global {name} holds a reference"))),
+            }
+        }
+
+        let data = env.as_ref(store);
+        if data.fs.open_files() > 0 {
+            return Err(OcrError::Other("snapshot: the guest has open files".to_string()));
+        }
+        let emval = data.emval.values().map_err(|e| OcrError::Other(format!("snapshot: {e}")))?;
+
+        let view = data.memory_view(store);
+        let memory_size = view.data_size();
+        let mut pages = Vec::new();
+        let mut page = vec![0_u8; PAGE_SIZE as usize];
+        for index in 0..memory_size / PAGE_SIZE {
+            view.read(index * PAGE_SIZE, &mut page).map_err(|e| OcrError::Other(format!("snapshot: {e}")))?;
+            if page.iter().any(|b| *b != 0) {
+                pages.push((index, page.clone().into_boxed_slice()));
+            }
+        }
+
+        Ok(Self {
+            memory_size,
+            memory: Arc::new(pages),
+            globals,
+            registry: data.registry.clone(),
+            classes: data.classes.clone(),
+            emval,
+            tzset_called: data.time.tzset_called,
+            engine,
+            model_loaded,
+            image_loaded,
+        })
+    }
+
+    /// Puts the state into `instance`, freshly linked and not initialized
+    pub(crate) fn restore(
+        &self,
+        store: &mut Store,
+        instance: &Instance,
+        env: &FunctionEnv<EmscriptenEnv>,
+    ) -> Result<(), OcrError> {
+        let error = |e: String| OcrError::Other(format!("restoring snapshot: {e}"));
+
+        let memory = env.as_ref(store).memory().clone();
+        let fresh_size = memory.view(&*store).data_size();
+        let max_size = env.as_ref(store).heap.max_size;
+        if self.memory_size > max_size {
+            return Err(error(format!(
+                "memory of {} bytes is larger than the heap limit of {max_size} bytes",
+                self.memory_size,
+            )));
+        }
+        if fresh_size > self.memory_size {
+            return Err(error(format!("memory of {fresh_size} bytes is larger than the snapshot")));
+        }
+        if fresh_size < self.memory_size {
+            let delta = (self.memory_size - fresh_size) / PAGE_SIZE;
+            memory.grow(&mut *store, Pages(delta as u32)).map_err(|e| error(e.to_string()))?;
+        }
+
+        write_pages(store, &memory, &self.memory, fresh_size).map_err(error)?;
+
+        for (name, value) in &self.globals {
+            let global = instance.exports.get_global(name).map_err(|e| error(format!("global {name}: {e}")))?;
+            global.set(&mut *store, value.clone()).map_err(|e| error(format!("global {name}: {}", e.message())))?;
+        }
+
+        let data = env.as_mut(store);
+        data.registry = self.registry.clone();
+        data.classes = self.classes.clone();
+        data.emval = HandleTable::from(&self.emval);
+        data.time.tzset_called = self.tzset_called;
+        Ok(())
+    }
+}
+
+/// Copies `pages` into `memory`, clearing the other pages of the
+/// `fresh_size` bytes a new instance starts with
+fn write_pages(store: &Store, memory: &Memory, pages: &[(u64, Box<[u8]>)], fresh_size: u64) -> Result<(), String> {
+    let view = memory.view(store);
+    // the initial pages hold the data segments of the new instance,
+    // the ones that are zero in the snapshot have to be cleared
+    let zero = vec![0_u8; PAGE_SIZE as usize];
+    let fresh_pages = fresh_size / PAGE_SIZE;
+    let mut next = 0;
+    for (index, page) in pages {
+        for cleared in next..(*index).min(fresh_pages) {
+            view.write(cleared * PAGE_SIZE, &zero).map_err(|e| e.to_string())?;
+        }
+        view.write(index * PAGE_SIZE, page).map_err(|e| e.to_string())?;
+        next = index + 1;
+    }
+    for cleared in next..fresh_pages {
+        view.write(cleared * PAGE_SIZE, &zero).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{Cranelift, EngineBuilder, Module, imports};
+    use crate::heap::HeapEnv;
+    use super::*;
+
+    /// `$sp` is internal like the stack pointer, `hello` is a data segment
+    /// in the first page
+    const SNAPSHOT_GUEST: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $sp (mut i32) (i32.const 1024))
+            (data (i32.const 16) "hello")
+            (func (export "sp") (result i32) (global.get $sp))
+            (func (export "set_sp") (param i32) (global.set $sp (local.get 0)))
+            (func (export "clear") (i64.store (i32.const 16) (i64.const 0)))
+            (func (export "grow") (result i32) (memory.grow (i32.const 1))))
+    "#;
+
+    fn store_and_module() -> (Store, Module) {
+        let mut compiler = Cranelift::default();
+        instrument(&mut compiler);
+        let store = Store::new_with_engine(&EngineBuilder::new(compiler).engine());
+        let module = Module::new(&store, SNAPSHOT_GUEST).unwrap();
+        (store, module)
+    }
+
+    fn guest(store: &mut Store, module: &Module) -> (FunctionEnv<EmscriptenEnv>, Instance) {
+        let instance = Instance::new(store, module, &imports! {}).unwrap();
+        let mut env = EmscriptenEnv::new();
+        env.set_memory(instance.exports.get_memory("memory").unwrap().clone());
+        (FunctionEnv::new(store, env), instance)
+    }
+
+    fn call(store: &mut Store, instance: &Instance, name: &str, args: &[Value]) -> Option<i32> {
+        let f = instance.exports.get_function(name).unwrap();
+        f.call(store, args).unwrap().first().and_then(Value::i32)
+    }
+
+    fn read(store: &Store, env: &FunctionEnv<EmscriptenEnv>, offset: u64) -> u8 {
+        let mut byte = [0];
+        env.as_ref(store).memory_view(store).read(offset, &mut byte).unwrap();
+        byte[0]
+    }
+
+    #[test]
+    fn restores_memory_and_internal_globals() {
+        let (mut store, module) = store_and_module();
+        let (env, instance) = guest(&mut store, &module);
+        call(&mut store, &instance, "set_sp", &[Value::I32(2048)]);
+        call(&mut store, &instance, "clear", &[]);
+        call(&mut store, &instance, "grow", &[]);
+        env.as_ref(&store).memory_view(&store).write(PAGE_SIZE + 8, &[42]).unwrap();
+        let snapshot = EngineSnapshot::capture(&mut store, &instance, &env, 0, false, false).unwrap();
+        assert_eq!(snapshot.memory_size, 2 * PAGE_SIZE);
+
+        let (env, instance) = guest(&mut store, &module);
+        assert_eq!(read(&store, &env, 16), b'h');
+        snapshot.restore(&mut store, &instance, &env).unwrap();
+        assert_eq!(call(&mut store, &instance, "sp", &[]), Some(2048));
+        // the first page is all zero in the snapshot, its data segment
+        // has to be cleared
+        assert_eq!(read(&store, &env, 16), 0);
+        assert_eq!(read(&store, &env, 20), 0);
+        assert_eq!(env.as_ref(&store).memory_view(&store).data_size(), 2 * PAGE_SIZE);
+        assert_eq!(read(&store, &env, PAGE_SIZE + 8), 42);
+    }
+
+    #[test]
+    fn refuses_memory_above_the_heap_limit() {
+        let (mut store, module) = store_and_module();
+        let (env, instance) = guest(&mut store, &module);
+        call(&mut store, &instance, "grow", &[]);
+        let snapshot = EngineSnapshot::capture(&mut store, &instance, &env, 0, false, false).unwrap();
+
+        let (env, instance) = guest(&mut store, &module);
+        env.as_mut(&mut store).heap = HeapEnv::new(PAGE_SIZE);
+        let error = snapshot.restore(&mut store, &instance, &env).unwrap_err();
+        assert!(error.to_string().contains("heap limit"), "{error}");
+        assert_eq!(env.as_ref(&store).memory_view(&store).data_size(), PAGE_SIZE);
+    }
+}
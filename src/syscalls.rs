@@ -0,0 +1,859 @@
+//! Emscripten syscalls over the WASI in-memory filesystem
+//!
+//! The module is linked against emscripten's musl, which calls
+//! `___syscall_*` for path based operations and the WASI style `_fd_*`
+//! functions for stream I/O. Both are served from an in-memory
+//! `MemFileSystem` private to the instance. The module imports no WASI
+//! functions, so there is no WASI env to share it with: the `FileTable` of
+//! the `EmscriptenEnv` is the guest's filesystem.
+//!
+//! The first model and the images are passed in through `OCREngine`. The
+//! filesystem holds what tesseract opens by path: the other languages and
+//! `osd`, mounted under `TESSDATA_DIR` by `OcrEngine::load_models`, and
+//! scratch files. The guest's standard streams are captured up to
+//! `CAPTURED_OUTPUT_SIZE`. The renderers that would write `output.hocr` and
+//! the like are not part of the build, the outputs come from `OCREngine`.
+//!
+//! emscripten uses the WASI errno numbering: `___syscall_*` return `-errno`,
+//! `_fd_*` return `errno` and pass results through out pointers.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use wasmer::{FunctionEnvMut, MemorySize, MemoryView, RuntimeError, WasmPtr};
+use wasmer_vfs::{FileSystem, FsError, VirtualFile, mem_fs::FileSystem as MemFileSystem};
+use wasmer_wasi::types::wasi::Errno;
+use crate::env::{EmscriptenEnv, read_c_string, read_u32, trap, write_u32};
+use crate::output::GuestOutput;
+
+/// `dirfd` meaning "relative to the working directory"
+pub const AT_FDCWD: i32 = -100;
+const AT_REMOVEDIR: u32 = 0x200;
+
+const O_ACCMODE: u32 = 0o3;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const O_DIRECTORY: u32 = 0o200000;
+
+const F_DUPFD: u32 = 0;
+const F_GETFD: u32 = 1;
+const F_SETFD: u32 = 2;
+const F_GETFL: u32 = 3;
+const F_SETFL: u32 = 4;
+const F_GETLK: u32 = 5;
+const F_SETLK: u32 = 6;
+const F_SETLKW: u32 = 7;
+const F_UNLCK: u16 = 2;
+
+const TCGETA: u32 = 0x5405;
+const TCGETS: u32 = 0x5401;
+const TCSETA: u32 = 0x5406;
+const TCSETAW: u32 = 0x5407;
+const TCSETAF: u32 = 0x5408;
+const TCSETS: u32 = 0x5402;
+const TCSETSW: u32 = 0x5403;
+const TCSETSF: u32 = 0x5404;
+const TIOCGPGRP: u32 = 0x540f;
+const TIOCSPGRP: u32 = 0x5410;
+const TIOCGWINSZ: u32 = 0x5413;
+const TIOCSWINSZ: u32 = 0x5414;
+
+/// How much of the guest's stdout / stderr is kept for error reports
+pub const OUTPUT_TAIL_SIZE: usize = 8 * 1024;
+
+/// How much of the guest's stdout and of its stderr `take_output` returns,
+/// the rest of a call's output is dropped
+pub const CAPTURED_OUTPUT_SIZE: usize = 1024 * 1024;
+
+/// Host buffer of `_fd_read` and `_fd_write`, the most copied at a time
+const IO_CHUNK_SIZE: usize = 64 * 1024;
+
+const STDIN: u32 = 0;
+const STDOUT: u32 = 1;
+const STDERR: u32 = 2;
+
+/// An entry of the fd table (`FS.streams` in the JS glue)
+#[derive(Debug)]
+enum OpenFile {
+    /// fds 0 - 2, terminals as far as the guest is concerned
+    Stdio,
+    File { file: Box<dyn VirtualFile + Send + Sync>, flags: u32 },
+    Dir { path: PathBuf, flags: u32 },
+}
+
+/// The guest's view of the filesystem: open fds and the working directory
+#[derive(Debug)]
+pub struct FileTable {
+    fs: MemFileSystem,
+    cwd: PathBuf,
+    fds: BTreeMap<u32, OpenFile>,
+    /// `printCharBuffers`: unterminated lines written to stdout / stderr
+    line_buffers: [Vec<u8>; 2],
+    /// what was written to stdout / stderr since the last `take_output`,
+    /// up to `CAPTURED_OUTPUT_SIZE` each
+    captured: [Vec<u8>; 2],
+    /// the last `OUTPUT_TAIL_SIZE` bytes written to stdout and stderr
+    output_tail: VecDeque<u8>,
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self::new(MemFileSystem::default())
+    }
+}
+
+impl FileTable {
+    /// Fd table over `fs` with only the standard streams open, working
+    /// directory `/`
+    pub fn new(fs: MemFileSystem) -> Self {
+        let fds = [STDIN, STDOUT, STDERR].into_iter()
+            .map(|fd| (fd, OpenFile::Stdio))
+            .collect();
+        Self {
+            fs,
+            cwd: PathBuf::from("/"),
+            fds,
+            line_buffers: Default::default(),
+            captured: Default::default(),
+            output_tail: VecDeque::new(),
+        }
+    }
+
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+
+    /// Number of open fds besides the standard streams
+    pub fn open_files(&self) -> usize {
+        self.fds.values().filter(|f| !matches!(f, OpenFile::Stdio)).count()
+    }
+
+    /// What the guest printed since the last call, unterminated lines included
+    pub fn take_output(&mut self) -> GuestOutput {
+        for fd in [STDOUT, STDERR] {
+            if !self.line_buffers[fd as usize - 1].is_empty() {
+                self.flush_line(fd);
+            }
+        }
+        let [stdout, stderr] = std::mem::take(&mut self.captured);
+        GuestOutput {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        }
+    }
+
+    /// The end of what the guest printed, stdout and stderr interleaved
+    pub fn output_tail(&self) -> String {
+        let (a, b) = self.output_tail.as_slices();
+        String::from_utf8_lossy(&[a, b].concat()).into_owned()
+    }
+
+    /// Creates the file at the absolute `path` with `data`, and its parent
+    /// directories, replacing an existing file
+    pub fn mount(&mut self, path: &Path, data: &[u8]) -> Result<(), Errno> {
+        for dir in path.ancestors().skip(1).collect::<Vec<_>>().into_iter().rev() {
+            if !self.is_dir(dir) {
+                self.fs.create_dir(dir).map_err(fs_errno)?;
+            }
+        }
+        let mut file = self.fs.new_open_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(fs_errno)?;
+        file.write_all(data).map_err(io_errno)
+    }
+
+    /// Absolute, normalized path of `path` relative to `dirfd`
+    /// (`SYSCALLS.calculateAt`)
+    fn resolve(&self, dirfd: i32, path: &[u8]) -> Result<PathBuf, Errno> {
+        if path.is_empty() {
+            return Err(Errno::Noent);
+        }
+        let path = String::from_utf8_lossy(path);
+        let base = if path.starts_with('/') {
+            PathBuf::from("/")
+        } else if dirfd == AT_FDCWD {
+            self.cwd.clone()
+        } else {
+            match self.fds.get(&(dirfd as u32)) {
+                Some(OpenFile::Dir { path, .. }) => path.clone(),
+                Some(_) => return Err(Errno::Notdir),
+                None => return Err(Errno::Badf),
+            }
+        };
+        let mut resolved = base;
+        for component in path.split('/') {
+            match component {
+                "" | "." => { },
+                ".." => { resolved.pop(); },
+                c => resolved.push(c),
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.fs.metadata(path).map(|m| m.is_dir()).unwrap_or(false)
+    }
+
+    fn insert(&mut self, file: OpenFile) -> u32 {
+        // lowest free fd, like `FS.nextfd`
+        let fd = (0..).find(|fd| !self.fds.contains_key(fd)).unwrap_or(u32::MAX);
+        self.fds.insert(fd, file);
+        fd
+    }
+
+    fn get(&mut self, fd: u32) -> Result<&mut OpenFile, Errno> {
+        self.fds.get_mut(&fd).ok_or(Errno::Badf)
+    }
+
+    pub fn open(&mut self, dirfd: i32, path: &[u8], flags: u32) -> Result<u32, Errno> {
+        let path = self.resolve(dirfd, path)?;
+        if self.is_dir(&path) {
+            if flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+                return Err(Errno::Exist);
+            }
+            if flags & O_ACCMODE != 0 || flags & O_TRUNC != 0 {
+                return Err(Errno::Isdir);
+            }
+            return Ok(self.insert(OpenFile::Dir { path, flags }));
+        }
+        if flags & O_DIRECTORY != 0 {
+            return Err(if self.fs.metadata(&path).is_ok() { Errno::Notdir } else { Errno::Noent });
+        }
+        // checked up front, the in-memory FS fails missing files as `PermissionDenied`
+        if flags & O_CREAT == 0 && self.fs.metadata(&path).is_err() {
+            return Err(Errno::Noent);
+        }
+        let access = flags & O_ACCMODE;
+        let write = access == O_WRONLY || access == O_RDWR;
+        let file = self.fs.new_open_options()
+            .read(access != O_WRONLY)
+            .write(write || flags & O_APPEND != 0)
+            .append(flags & O_APPEND != 0)
+            .truncate(write && flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .open(&path)
+            .map_err(fs_errno)?;
+        Ok(self.insert(OpenFile::File { file, flags }))
+    }
+
+    pub fn close(&mut self, fd: u32) -> Result<(), Errno> {
+        match self.fds.remove(&fd) {
+            Some(OpenFile::File { mut file, .. }) => file.flush().map_err(io_errno),
+            Some(OpenFile::Stdio) => {
+                self.flush_line(fd);
+                Ok(())
+            },
+            Some(OpenFile::Dir { .. }) => Ok(()),
+            None => Err(Errno::Badf),
+        }
+    }
+
+    /// Fills `buf` as far as the file goes, returns the number of bytes read
+    pub fn read(&mut self, fd: u32, buf: &mut [u8]) -> Result<usize, Errno> {
+        match self.get(fd)? {
+            // nothing is ever typed into the guest's terminal
+            OpenFile::Stdio => Ok(0),
+            OpenFile::File { file, flags } => {
+                if *flags & O_ACCMODE == O_WRONLY {
+                    return Err(Errno::Badf);
+                }
+                let mut read = 0;
+                while read < buf.len() {
+                    match file.read(&mut buf[read..]).map_err(io_errno)? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+                Ok(read)
+            },
+            OpenFile::Dir { .. } => Err(Errno::Isdir),
+        }
+    }
+
+    pub fn write(&mut self, fd: u32, data: &[u8]) -> Result<usize, Errno> {
+        match self.get(fd)? {
+            OpenFile::Stdio if fd == STDOUT || fd == STDERR => {
+                self.output_tail.extend(data);
+                let excess = self.output_tail.len().saturating_sub(OUTPUT_TAIL_SIZE);
+                self.output_tail.drain(..excess);
+                let captured = &mut self.captured[fd as usize - 1];
+                let room = CAPTURED_OUTPUT_SIZE.saturating_sub(captured.len());
+                captured.extend_from_slice(&data[..data.len().min(room)]);
+                for &c in data {
+                    self.print_char(fd, c);
+                }
+                Ok(data.len())
+            },
+            OpenFile::Stdio => Err(Errno::Badf),
+            OpenFile::File { file, flags } => {
+                if *flags & O_ACCMODE == 0 && *flags & O_APPEND == 0 {
+                    return Err(Errno::Badf);
+                }
+                file.write_all(data).map_err(io_errno)?;
+                Ok(data.len())
+            },
+            OpenFile::Dir { .. } => Err(Errno::Isdir),
+        }
+    }
+
+    pub fn seek(&mut self, fd: u32, offset: i64, whence: u32) -> Result<u64, Errno> {
+        let file = match self.get(fd)? {
+            OpenFile::File { file, .. } => file,
+            OpenFile::Stdio => return Err(Errno::Spipe),
+            OpenFile::Dir { .. } => return Err(Errno::Isdir),
+        };
+        let pos = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(Errno::Inval),
+        };
+        file.seek(pos).map_err(io_errno)
+    }
+
+    pub fn flags(&mut self, fd: u32) -> Result<u32, Errno> {
+        Ok(match self.get(fd)? {
+            OpenFile::Stdio => if fd == STDIN { 0 } else { O_WRONLY },
+            OpenFile::File { flags, .. } | OpenFile::Dir { flags, .. } => *flags,
+        })
+    }
+
+    /// `F_SETFL`, the JS glue only ever adds flags
+    pub fn add_flags(&mut self, fd: u32, new_flags: u32) -> Result<(), Errno> {
+        match self.get(fd)? {
+            OpenFile::Stdio => { },
+            OpenFile::File { flags, .. } | OpenFile::Dir { flags, .. } => *flags |= new_flags,
+        }
+        Ok(())
+    }
+
+    pub fn is_tty(&mut self, fd: u32) -> Result<bool, Errno> {
+        Ok(matches!(self.get(fd)?, OpenFile::Stdio))
+    }
+
+    pub fn rmdir(&mut self, dirfd: i32, path: &[u8]) -> Result<(), Errno> {
+        let path = self.resolve(dirfd, path)?;
+        if path == Path::new("/") || path == self.cwd {
+            return Err(Errno::Busy);
+        }
+        if !self.is_dir(&path) {
+            return Err(if self.fs.metadata(&path).is_ok() { Errno::Notdir } else { Errno::Noent });
+        }
+        self.fs.remove_dir(&path).map_err(fs_errno)
+    }
+
+    pub fn unlink(&mut self, dirfd: i32, path: &[u8]) -> Result<(), Errno> {
+        let path = self.resolve(dirfd, path)?;
+        // checked up front, the in-memory FS fails missing files as `NotAFile`
+        match self.fs.metadata(&path) {
+            Err(_) => Err(Errno::Noent),
+            Ok(m) if m.is_dir() => Err(Errno::Isdir),
+            Ok(_) => self.fs.remove_file(&path).map_err(fs_errno),
+        }
+    }
+
+    /// `printChar`: stdout / stderr are forwarded line by line
+    fn print_char(&mut self, fd: u32, c: u8) {
+        if c == 0 || c == b'\n' {
+            self.flush_line(fd);
+        } else {
+            self.line_buffers[fd as usize - 1].push(c);
+            // a guest that never ends its line is logged in pieces
+            if self.line_buffers[fd as usize - 1].len() >= OUTPUT_TAIL_SIZE {
+                self.flush_line(fd);
+            }
+        }
+    }
+
+    /// Hands a complete line to the `log` crate (target `tesseract`,
+    /// stdout at info, stderr at warn level) with the `log` feature
+    fn flush_line(&mut self, fd: u32) {
+        if fd != STDOUT && fd != STDERR {
+            return;
+        }
+        let line = std::mem::take(&mut self.line_buffers[fd as usize - 1]);
+        #[cfg(feature = "log")]
+        {
+            let line = String::from_utf8_lossy(&line);
+            if fd == STDOUT {
+                log::info!(target: "tesseract", "{line}");
+            } else {
+                log::warn!(target: "tesseract", "{line}");
+            }
+        }
+        #[cfg(not(feature = "log"))]
+        drop(line);
+    }
+}
+
+fn fs_errno(e: FsError) -> Errno {
+    match e {
+        FsError::EntityNotFound => Errno::Noent,
+        FsError::AlreadyExists => Errno::Exist,
+        FsError::BaseNotDirectory => Errno::Notdir,
+        FsError::NotAFile => Errno::Isdir,
+        FsError::DirectoryNotEmpty => Errno::Notempty,
+        FsError::InvalidFd => Errno::Badf,
+        FsError::InvalidInput => Errno::Inval,
+        FsError::PermissionDenied => Errno::Access,
+        _ => Errno::Io,
+    }
+}
+
+fn io_errno(e: io::Error) -> Errno {
+    match e.kind() {
+        io::ErrorKind::NotFound => Errno::Noent,
+        io::ErrorKind::PermissionDenied => Errno::Access,
+        io::ErrorKind::InvalidInput => Errno::Inval,
+        _ => Errno::Io,
+    }
+}
+
+/// `-errno` as returned by the `___syscall_*` functions
+fn syscall_result(result: Result<i32, Errno>) -> i32 {
+    result.unwrap_or_else(|e| -(e as i32))
+}
+
+fn errno(result: Result<(), Errno>) -> u32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e as u32,
+    }
+}
+
+/// The `(ptr, len)` pairs of an `iovec` array
+fn read_iovecs(view: &MemoryView, iov: u64, iovcnt: u32) -> Result<Vec<(u32, u32)>, RuntimeError> {
+    (0..iovcnt as u64)
+        .map(|i| {
+            let ptr = read_u32(view, iov + i * 8).map_err(trap)?;
+            let len = read_u32(view, iov + i * 8 + 4).map_err(trap)?;
+            Ok((ptr, len))
+        })
+        .collect()
+}
+
+fn read_path(ctx: &FunctionEnvMut<'_, EmscriptenEnv>, path: u64) -> Result<Vec<u8>, RuntimeError> {
+    let view = ctx.data().memory_view(ctx);
+    read_c_string(&view, path).map_err(trap)
+}
+
+// ----------
+//
+// ___syscall_openat(dirfd, path, flags, varargs) -> fd
+pub fn ___syscall_openat<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    dirfd: i32,
+    path: WasmPtr<u8, M>,
+    flags: u32,
+    _varargs: WasmPtr<u8, M>,
+) -> Result<i32, RuntimeError> {
+    // varargs only holds the mode, which the in-memory FS doesn't track
+    let path = read_path(&ctx, path.offset().into())?;
+    let fd = ctx.data_mut().fs.open(dirfd, &path, flags);
+    Ok(syscall_result(fd.map(|fd| fd as i32)))
+}
+
+// ----------
+//
+// ___syscall_fcntl64(fd, cmd, varargs)
+pub fn ___syscall_fcntl64<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    fd: u32,
+    cmd: u32,
+    varargs: WasmPtr<u8, M>,
+) -> Result<i32, RuntimeError> {
+    let flags = match ctx.data_mut().fs.flags(fd) {
+        Ok(flags) => flags,
+        Err(e) => return Ok(-(e as i32)),
+    };
+    let result = match cmd {
+        // the fd table is not shared with anything that could use a duplicate
+        F_DUPFD => Err(Errno::Inval),
+        // FD_CLOEXEC makes no sense for a single process
+        F_GETFD | F_SETFD => Ok(0),
+        F_GETFL => Ok(flags as i32),
+        F_SETFL => {
+            let arg = {
+                let view = ctx.data().memory_view(&ctx);
+                read_u32(&view, varargs.offset().into()).map_err(trap)?
+            };
+            ctx.data_mut().fs.add_flags(fd, arg).map(|_| 0)
+        },
+        F_GETLK => {
+            // nothing is ever locked
+            let view = ctx.data().memory_view(&ctx);
+            let lock = read_u32(&view, varargs.offset().into()).map_err(trap)?;
+            view.write(lock as u64, &F_UNLCK.to_le_bytes()).map_err(trap)?;
+            Ok(0)
+        },
+        F_SETLK | F_SETLKW => Ok(0),
+        _ => Err(Errno::Inval),
+    };
+    Ok(syscall_result(result))
+}
+
+// ----------
+//
+// ___syscall_ioctl(fd, op, varargs)
+pub fn ___syscall_ioctl<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    fd: u32,
+    op: u32,
+    varargs: WasmPtr<u8, M>,
+) -> Result<i32, RuntimeError> {
+    match ctx.data_mut().fs.is_tty(fd) {
+        Ok(true) => { },
+        Ok(false) => return Ok(-(Errno::Notty as i32)),
+        Err(e) => return Ok(-(e as i32)),
+    }
+    let result = match op {
+        TCGETA | TCGETS | TCSETA | TCSETAW | TCSETAF | TCSETS | TCSETSW | TCSETSF => Ok(0),
+        TIOCGWINSZ | TIOCSWINSZ | TIOCSPGRP => Ok(0),
+        TIOCGPGRP => {
+            let view = ctx.data().memory_view(&ctx);
+            let argp = read_u32(&view, varargs.offset().into()).map_err(trap)?;
+            write_u32(&view, argp as u64, 0).map_err(trap)?;
+            Ok(0)
+        },
+        _ => Err(Errno::Inval),
+    };
+    Ok(syscall_result(result))
+}
+
+// ----------
+//
+// ___syscall_getcwd(buf, size) -> length incl. the terminator
+pub fn ___syscall_getcwd<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    buf: WasmPtr<u8, M>,
+    size: u32,
+) -> Result<i32, RuntimeError> {
+    if size == 0 {
+        return Ok(-(Errno::Inval as i32));
+    }
+    let mut cwd = ctx.data().fs.cwd().to_string_lossy().into_owned().into_bytes();
+    cwd.push(0);
+    if (size as usize) < cwd.len() {
+        return Ok(-(Errno::Range as i32));
+    }
+    let view = ctx.data().memory_view(&ctx);
+    view.write(buf.offset().into(), &cwd).map_err(trap)?;
+    Ok(cwd.len() as i32)
+}
+
+// ----------
+//
+// ___syscall_rmdir(path)
+pub fn ___syscall_rmdir<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    path: WasmPtr<u8, M>,
+) -> Result<i32, RuntimeError> {
+    let path = read_path(&ctx, path.offset().into())?;
+    let result = ctx.data_mut().fs.rmdir(AT_FDCWD, &path);
+    Ok(syscall_result(result.map(|_| 0)))
+}
+
+// ----------
+//
+// ___syscall_unlinkat(dirfd, path, flags)
+pub fn ___syscall_unlinkat<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    dirfd: i32,
+    path: WasmPtr<u8, M>,
+    flags: u32,
+) -> Result<i32, RuntimeError> {
+    let path = read_path(&ctx, path.offset().into())?;
+    let fs = &mut ctx.data_mut().fs;
+    let result = match flags {
+        0 => fs.unlink(dirfd, &path),
+        AT_REMOVEDIR => fs.rmdir(dirfd, &path),
+        _ => Err(Errno::Inval),
+    };
+    Ok(syscall_result(result.map(|_| 0)))
+}
+
+// ----------
+//
+// _fd_close(fd) -> errno
+pub fn _fd_close<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    fd: u32,
+) -> u32 {
+    errno(ctx.data_mut().fs.close(fd))
+}
+
+// ----------
+//
+// _fd_read(fd, iov, iovcnt, pnum) -> errno
+pub fn _fd_read<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    fd: u32,
+    iov: WasmPtr<u8, M>,
+    iovcnt: u32,
+    pnum: WasmPtr<u8, M>,
+) -> Result<u32, RuntimeError> {
+    let iovecs = {
+        let view = ctx.data().memory_view(&ctx);
+        read_iovecs(&view, iov.offset().into(), iovcnt)?
+    };
+    // the iov lengths are up to the guest, go through a bounded buffer
+    let mut buf = vec![0_u8; IO_CHUNK_SIZE];
+    let mut total = 0_u32;
+    'iovecs: for (ptr, len) in iovecs {
+        let mut offset = 0;
+        while offset < len {
+            let chunk = (len - offset).min(IO_CHUNK_SIZE as u32) as usize;
+            let n = match ctx.data_mut().fs.read(fd, &mut buf[..chunk]) {
+                Ok(n) => n,
+                // what was read so far is not lost
+                Err(_) if total > 0 => break 'iovecs,
+                Err(e) => return Ok(e as u32),
+            };
+            let view = ctx.data().memory_view(&ctx);
+            view.write(ptr as u64 + offset as u64, &buf[..n]).map_err(trap)?;
+            total += n as u32;
+            offset += n as u32;
+            if n < chunk {
+                break 'iovecs;
+            }
+        }
+    }
+    let view = ctx.data().memory_view(&ctx);
+    write_u32(&view, pnum.offset().into(), total).map_err(trap)?;
+    Ok(0)
+}
+
+// ----------
+//
+// _fd_write(fd, iov, iovcnt, pnum) -> errno
+pub fn _fd_write<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    fd: u32,
+    iov: WasmPtr<u8, M>,
+    iovcnt: u32,
+    pnum: WasmPtr<u8, M>,
+) -> Result<u32, RuntimeError> {
+    let iovecs = {
+        let view = ctx.data().memory_view(&ctx);
+        read_iovecs(&view, iov.offset().into(), iovcnt)?
+    };
+    // like `_fd_read`, through a bounded buffer
+    let mut buf = vec![0_u8; IO_CHUNK_SIZE];
+    let mut total = 0_u32;
+    'iovecs: for (ptr, len) in iovecs {
+        let mut offset = 0;
+        while offset < len {
+            let chunk = (len - offset).min(IO_CHUNK_SIZE as u32) as usize;
+            {
+                let view = ctx.data().memory_view(&ctx);
+                view.read(ptr as u64 + offset as u64, &mut buf[..chunk]).map_err(trap)?;
+            }
+            match ctx.data_mut().fs.write(fd, &buf[..chunk]) {
+                Ok(n) => {
+                    total += n as u32;
+                    offset += n as u32;
+                },
+                // what was written so far is not lost
+                Err(_) if total > 0 => break 'iovecs,
+                Err(e) => return Ok(e as u32),
+            }
+        }
+    }
+    let view = ctx.data().memory_view(&ctx);
+    write_u32(&view, pnum.offset().into(), total).map_err(trap)?;
+    Ok(0)
+}
+
+// ----------
+//
+// _fd_seek(fd, offset_low, offset_high, whence, newOffset) -> errno
+pub fn _fd_seek<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    fd: u32,
+    offset_low: u32,
+    offset_high: i32,
+    whence: u32,
+    new_offset: WasmPtr<u8, M>,
+) -> Result<u32, RuntimeError> {
+    // the i64 offset is legalized into two i32 halves
+    let offset = ((offset_high as i64) << 32) | offset_low as i64;
+    let position = match ctx.data_mut().fs.seek(fd, offset, whence) {
+        Ok(p) => p,
+        Err(e) => return Ok(e as u32),
+    };
+    let view = ctx.data().memory_view(&ctx);
+    view.write(new_offset.offset().into(), &position.to_le_bytes()).map_err(trap)?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{Function, Memory32, Pages, Value};
+    use crate::env::testing;
+    use super::*;
+
+    #[test]
+    fn unknown_fds() {
+        let mut fs = FileTable::default();
+        assert_eq!(fs.read(7, &mut [0; 4]), Err(Errno::Badf));
+        assert_eq!(fs.write(7, b"x"), Err(Errno::Badf));
+        assert_eq!(fs.seek(7, 0, 0), Err(Errno::Badf));
+        assert_eq!(fs.flags(7), Err(Errno::Badf));
+        assert_eq!(fs.close(7), Err(Errno::Badf));
+        assert_eq!(fs.open(7, b"file", 0), Err(Errno::Badf));
+        // stdin can't be written, stdout not seeked
+        assert_eq!(fs.write(STDIN, b"x"), Err(Errno::Badf));
+        assert_eq!(fs.seek(STDOUT, 0, 0), Err(Errno::Spipe));
+        assert_eq!(fs.read(STDIN, &mut [0; 4]), Ok(0));
+        fs.close(STDERR).unwrap();
+        assert_eq!(fs.write(STDERR, b"x"), Err(Errno::Badf));
+    }
+
+    #[test]
+    fn missing_paths() {
+        let mut fs = FileTable::default();
+        assert_eq!(fs.open(AT_FDCWD, b"", 0), Err(Errno::Noent));
+        assert_eq!(fs.open(AT_FDCWD, b"/missing", 0), Err(Errno::Noent));
+        assert_eq!(fs.open(AT_FDCWD, b"missing", O_DIRECTORY), Err(Errno::Noent));
+        assert_eq!(fs.unlink(AT_FDCWD, b"/missing"), Err(Errno::Noent));
+        assert_eq!(fs.rmdir(AT_FDCWD, b"/missing"), Err(Errno::Noent));
+        assert_eq!(fs.rmdir(AT_FDCWD, b"/"), Err(Errno::Busy));
+        assert_eq!(fs.unlink(AT_FDCWD, b"/"), Err(Errno::Isdir));
+        assert_eq!(fs.open_files(), 0);
+    }
+
+    #[test]
+    fn read_write_files() {
+        let mut fs = FileTable::default();
+        let fd = fs.open(AT_FDCWD, b"/a/../scratch", O_CREAT | O_RDWR).unwrap();
+        assert_eq!(fd, 3);
+        assert_eq!(fs.write(fd, b"hello"), Ok(5));
+        assert_eq!(fs.seek(fd, 1, 0), Ok(1));
+        let mut buf = [0; 8];
+        assert_eq!(fs.read(fd, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"ello");
+        assert_eq!(fs.read(fd, &mut buf), Ok(0));
+        assert_eq!(fs.is_tty(fd), Ok(false));
+        fs.close(fd).unwrap();
+
+        assert_eq!(fs.open(AT_FDCWD, b"scratch", O_CREAT | O_EXCL | O_WRONLY), Err(Errno::Exist));
+        assert_eq!(fs.open(AT_FDCWD, b"scratch", O_DIRECTORY), Err(Errno::Notdir));
+        let fd = fs.open(AT_FDCWD, b"scratch", 0).unwrap();
+        assert_eq!(fs.write(fd, b"x"), Err(Errno::Badf));
+        let wfd = fs.open(AT_FDCWD, b"./scratch", O_WRONLY | O_APPEND).unwrap();
+        assert_eq!(wfd, 4);
+        assert_eq!(fs.read(wfd, &mut buf), Err(Errno::Badf));
+        assert_eq!(fs.write(wfd, b"!"), Ok(1));
+        fs.close(wfd).unwrap();
+        assert_eq!(fs.seek(fd, 0, 0), Ok(0));
+        assert_eq!(fs.read(fd, &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"hello!");
+        fs.close(fd).unwrap();
+
+        fs.unlink(AT_FDCWD, b"scratch").unwrap();
+        assert_eq!(fs.open(AT_FDCWD, b"scratch", 0), Err(Errno::Noent));
+        assert_eq!(fs.open_files(), 0);
+    }
+
+    #[test]
+    fn mounts_files() {
+        let mut fs = FileTable::default();
+        fs.mount(Path::new("/tessdata/script/Latin.traineddata"), b"latin").unwrap();
+        fs.mount(Path::new("/tessdata/eng.traineddata"), b"english").unwrap();
+        fs.mount(Path::new("/tessdata/eng.traineddata"), b"eng").unwrap();
+        let fd = fs.open(AT_FDCWD, b"/tessdata/script/Latin.traineddata", 0).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(fs.read(fd, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"latin");
+        let dir = fs.open(AT_FDCWD, b"/tessdata", O_DIRECTORY).unwrap();
+        let fd = fs.open(dir as i32, b"eng.traineddata", 0).unwrap();
+        assert_eq!(fs.read(fd, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"eng");
+    }
+
+    #[test]
+    fn directories() {
+        let mut fs = FileTable::default();
+        let dir = fs.open(AT_FDCWD, b"/", O_DIRECTORY).unwrap();
+        assert_eq!(fs.read(dir, &mut [0; 4]), Err(Errno::Isdir));
+        assert_eq!(fs.open(AT_FDCWD, b"/", O_RDWR), Err(Errno::Isdir));
+        let fd = fs.open(dir as i32, b"scratch", O_CREAT | O_WRONLY).unwrap();
+        assert_eq!(fs.open(fd as i32, b"other", 0), Err(Errno::Notdir));
+        assert_eq!(fs.rmdir(dir as i32, b"scratch"), Err(Errno::Notdir));
+        assert_eq!(fs.open_files(), 2);
+    }
+
+    #[test]
+    fn captures_output() {
+        let mut fs = FileTable::default();
+        fs.write(STDOUT, b"line\npartial").unwrap();
+        fs.write(STDERR, b"warning\n").unwrap();
+        let output = fs.take_output();
+        assert_eq!(output.stdout, "line\npartial");
+        assert_eq!(output.stderr, "warning\n");
+        assert_eq!(fs.take_output(), GuestOutput::default());
+        assert_eq!(fs.output_tail(), "line\npartialwarning\n");
+
+        fs.write(STDOUT, &vec![b'x'; OUTPUT_TAIL_SIZE]).unwrap();
+        assert_eq!(fs.output_tail().len(), OUTPUT_TAIL_SIZE);
+        assert!(!fs.output_tail().contains('w'));
+    }
+
+    #[test]
+    fn caps_captured_output() {
+        let mut fs = FileTable::default();
+        let line = vec![b'x'; CAPTURED_OUTPUT_SIZE - 1];
+        assert_eq!(fs.write(STDOUT, &line), Ok(line.len()));
+        assert_eq!(fs.write(STDOUT, b"yz\n"), Ok(3));
+        fs.write(STDERR, b"warning\n").unwrap();
+        let output = fs.take_output();
+        assert_eq!(output.stdout.len(), CAPTURED_OUTPUT_SIZE);
+        assert!(output.stdout.ends_with("xy"));
+        assert_eq!(output.stderr, "warning\n");
+        assert!(fs.line_buffers[0].is_empty());
+        fs.write(STDOUT, b"next").unwrap();
+        assert_eq!(fs.take_output().stdout, "next");
+    }
+
+    #[test]
+    fn writes_iovecs_in_chunks() {
+        let (mut store, env) = testing::env_with_memory();
+        let fd_write = Function::new_typed_with_env(&mut store, &env, _fd_write::<Memory32>);
+        let fd = env.as_mut(&mut store).fs.open(AT_FDCWD, b"/scratch", O_CREAT | O_WRONLY).unwrap();
+        let memory = env.as_ref(&store).memory().clone();
+        memory.grow(&mut store, Pages(2)).unwrap();
+        // two iovecs at 0 and 16, the second longer than a chunk
+        let view = env.as_ref(&store).memory_view(&store);
+        let data = (0..IO_CHUNK_SIZE as u32 + 100).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        view.write(1024, &data).unwrap();
+        for (i, word) in [64_u32, 3, 1024, data.len() as u32].into_iter().enumerate() {
+            write_u32(&view, 16 + i as u64 * 4, word).unwrap();
+        }
+        view.write(64, b"abc").unwrap();
+        let result = fd_write.call(&mut store, &[Value::I32(fd as i32), Value::I32(16), Value::I32(2), Value::I32(8)]).unwrap();
+        assert_eq!(result[0].i32(), Some(0));
+        let view = env.as_ref(&store).memory_view(&store);
+        assert_eq!(read_u32(&view, 8).unwrap(), 3 + data.len() as u32);
+
+        let fs = &mut env.as_mut(&mut store).fs;
+        fs.close(fd).unwrap();
+        let fd = fs.open(AT_FDCWD, b"/scratch", 0).unwrap();
+        let mut written = vec![0; data.len() + 16];
+        assert_eq!(fs.read(fd, &mut written), Ok(3 + data.len()));
+        assert_eq!(&written[..3], b"abc");
+        assert_eq!(&written[3..3 + data.len()], &data[..]);
+
+        // a bad fd fails before anything is written
+        let result = fd_write.call(&mut store, &[Value::I32(9), Value::I32(16), Value::I32(2), Value::I32(8)]).unwrap();
+        assert_eq!(result[0].i32(), Some(Errno::Badf as i32));
+    }
+}
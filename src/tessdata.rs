@@ -0,0 +1,313 @@
+//! Where the `.traineddata` models come from
+//!
+//! A run only loads the models its `OcrOptions` ask for. Models are looked
+//! up by name (`eng`, `osd`, `script/Latin`, ...) in the `TessdataSource`
+//! of the VM: a directory on disk, models held in memory, or the models
+//! compiled into the binary with the `embed-*` cargo features.
+//!
+//! `OCREngine.loadModel` takes a single model from memory. For combined
+//! languages the first model is loaded that way with
+//! `tessedit_load_sublangs` added to its config, and tesseract reads the
+//! other languages (and `osd`) from the guest filesystem, see
+//! `OcrEngine::load_models`.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use crate::error::OcrError;
+
+/// Index of the `lang.config` component in a `.traineddata` file
+/// (`TESSDATA_LANG_CONFIG`)
+const LANG_CONFIG: usize = 0;
+
+/// Contents of `.traineddata` files by model name, in load order
+pub type Models<'a> = Vec<(String, Cow<'a, [u8]>)>;
+
+/// Provider of `.traineddata` files
+pub trait TessdataSource: fmt::Debug + Send + Sync {
+    /// Contents of `{model}.traineddata`, `None` if the source doesn't have it
+    fn load(&self, model: &str) -> Result<Option<Cow<'_, [u8]>>, OcrError>;
+}
+
+/// Models read from a directory on every run, e.g. `/usr/share/tesseract-ocr/5/tessdata`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TessdataDir {
+    pub path: PathBuf,
+}
+
+impl TessdataDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl TessdataSource for TessdataDir {
+    fn load(&self, model: &str) -> Result<Option<Cow<'_, [u8]>>, OcrError> {
+        let path = self.path.join(format!("{model}.traineddata"));
+        match std::fs::read(&path) {
+            Ok(data) => Ok(Some(Cow::Owned(data))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(OcrError::Io { path, source }),
+        }
+    }
+}
+
+/// Models held in memory, e.g. downloaded at startup
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTessdata {
+    models: BTreeMap<String, Arc<[u8]>>,
+}
+
+impl MemoryTessdata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the contents of `{model}.traineddata`
+    pub fn with_model(mut self, model: &str, data: impl Into<Arc<[u8]>>) -> Self {
+        self.insert(model, data);
+        self
+    }
+
+    pub fn insert(&mut self, model: &str, data: impl Into<Arc<[u8]>>) {
+        self.models.insert(model.to_string(), data.into());
+    }
+}
+
+impl TessdataSource for MemoryTessdata {
+    fn load(&self, model: &str) -> Result<Option<Cow<'_, [u8]>>, OcrError> {
+        Ok(self.models.get(model).map(|data| Cow::Borrowed(&data[..])))
+    }
+}
+
+/// Models compiled into the binary, one cargo feature each
+/// (`embed-eng`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EmbeddedTessdata;
+
+static EMBEDDED_MODELS: &[(&str, &[u8])] = &[
+    #[cfg(feature = "embed-eng")]
+    ("eng", include_bytes!("../eng.traineddata")),
+];
+
+impl EmbeddedTessdata {
+    /// Names of the embedded models
+    pub fn models() -> impl Iterator<Item = &'static str> {
+        EMBEDDED_MODELS.iter().map(|(name, _)| *name)
+    }
+}
+
+impl TessdataSource for EmbeddedTessdata {
+    fn load(&self, model: &str) -> Result<Option<Cow<'_, [u8]>>, OcrError> {
+        Ok(EMBEDDED_MODELS.iter()
+            .find(|(name, _)| *name == model)
+            .map(|(_, data)| Cow::Borrowed(*data)))
+    }
+}
+
+/// Loads all of `models` from `source`, failing with the names of every
+/// model the source doesn't have
+pub fn load_all<'a>(source: &'a dyn TessdataSource, models: &[String]) -> Result<Models<'a>, OcrError> {
+    let mut loaded = Vec::new();
+    let mut missing = Vec::new();
+    for model in models {
+        match source.load(model)? {
+            Some(data) => loaded.push((model.clone(), data)),
+            None => missing.push(model.clone()),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(OcrError::MissingTrainedData { languages: missing });
+    }
+    Ok(loaded)
+}
+
+/// `model` with `tessedit_load_sublangs` set to `languages` in its config,
+/// so that tesseract loads them along with it
+///
+/// A `.traineddata` file is a little endian `i32` count of components,
+/// an `i64` offset per component (-1 if absent) and the components in
+/// order; the config is component 0, a text file of `name value` lines.
+pub fn with_sublanguages(model: &[u8], languages: &[&str]) -> Result<Vec<u8>, OcrError> {
+    if languages.is_empty() {
+        return Ok(model.to_vec());
+    }
+    let invalid = |e: &str| OcrError::InvalidTrainedData(e.to_string());
+    let count = model.get(..4)
+        .map(|b| i32::from_le_bytes(b.try_into().unwrap_or_default()))
+        .filter(|count| (LANG_CONFIG as i32 + 1..=1000).contains(count))
+        .ok_or_else(|| invalid("not a traineddata file"))? as usize;
+    let header_size = 4 + count * 8;
+    let offsets = model.get(4..header_size)
+        .ok_or_else(|| invalid("truncated component table"))?
+        .chunks(8)
+        .map(|b| i64::from_le_bytes(b.try_into().unwrap_or_default()))
+        .collect::<Vec<_>>();
+    let valid = offsets.iter()
+        .filter(|&&offset| offset >= 0)
+        .try_fold(header_size as i64, |prev, &offset| (prev..=model.len() as i64).contains(&offset).then_some(offset));
+    if valid.is_none() {
+        return Err(invalid("component offsets out of order"));
+    }
+
+    // the config stays where it is, or is inserted right after the header
+    let (config_start, old_config) = match offsets[LANG_CONFIG] {
+        -1 => (header_size, &b""[..]),
+        start => {
+            let end = offsets[LANG_CONFIG + 1..].iter()
+                .find(|&&offset| offset >= 0)
+                .map_or(model.len(), |&offset| offset as usize);
+            (start as usize, &model[start as usize..end])
+        },
+    };
+    let mut config = old_config.to_vec();
+    if !config.is_empty() && !config.ends_with(b"\n") {
+        config.push(b'\n');
+    }
+    config.extend_from_slice(format!("tessedit_load_sublangs {}\n", languages.join("+")).as_bytes());
+    let shift = (config.len() - old_config.len()) as i64;
+
+    let mut patched = Vec::with_capacity(model.len() + shift as usize);
+    patched.extend_from_slice(&model[..4]);
+    for (i, &offset) in offsets.iter().enumerate() {
+        let offset = match offset {
+            _ if i == LANG_CONFIG => config_start as i64,
+            -1 => -1,
+            offset => offset + shift,
+        };
+        patched.extend_from_slice(&offset.to_le_bytes());
+    }
+    patched.extend_from_slice(&model[header_size..config_start]);
+    patched.extend_from_slice(&config);
+    patched.extend_from_slice(&model[config_start + old_config.len()..]);
+    Ok(patched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `.traineddata` with the given components, `None` for absent ones
+    fn traineddata(components: &[Option<&[u8]>]) -> Vec<u8> {
+        let header_size = 4 + components.len() * 8;
+        let mut data = (components.len() as i32).to_le_bytes().to_vec();
+        let mut offset = header_size as i64;
+        for component in components {
+            match component {
+                Some(c) => {
+                    data.extend_from_slice(&offset.to_le_bytes());
+                    offset += c.len() as i64;
+                },
+                None => data.extend_from_slice(&(-1i64).to_le_bytes()),
+            }
+        }
+        for component in components.iter().flatten() {
+            data.extend_from_slice(component);
+        }
+        data
+    }
+
+    /// Loads `model` from `source` as the first of a combined language run
+    fn load_first(source: &dyn TessdataSource, model: &str) -> Result<Vec<u8>, OcrError> {
+        let models = load_all(source, &[model.to_string()])?;
+        with_sublanguages(&models[0].1, &["deu"])
+    }
+
+    #[test]
+    fn tessdata_dir() {
+        let dir = std::env::temp_dir().join(format!("tesseractwasmer-tessdata-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("dir.traineddata")).unwrap();
+        std::fs::write(dir.join("eng.traineddata"), traineddata(&[None, Some(b"unicharset")])).unwrap();
+        std::fs::write(dir.join("bad.traineddata"), b"garbage").unwrap();
+        let source = TessdataDir::new(&dir);
+
+        assert!(load_first(&source, "eng").is_ok());
+        assert!(matches!(load_first(&source, "missing"), Err(OcrError::MissingTrainedData { .. })));
+        assert!(matches!(load_first(&source, "bad"), Err(OcrError::InvalidTrainedData(_))));
+        match load_first(&source, "dir") {
+            Err(OcrError::Io { path, .. }) => assert_eq!(path, dir.join("dir.traineddata")),
+            other => panic!("expected Io, got {other:?}"),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn memory_tessdata() {
+        let mut source = MemoryTessdata::new().with_model("eng", traineddata(&[None, Some(b"unicharset")]));
+        source.insert("bad", &b"garbage"[..]);
+        assert!(load_first(&source, "eng").is_ok());
+        assert!(matches!(load_first(&source, "missing"), Err(OcrError::MissingTrainedData { .. })));
+        assert!(matches!(load_first(&source, "bad"), Err(OcrError::InvalidTrainedData(_))));
+    }
+
+    #[test]
+    fn embedded_tessdata() {
+        assert!(matches!(load_first(&EmbeddedTessdata, "missing"), Err(OcrError::MissingTrainedData { .. })));
+        for model in EmbeddedTessdata::models() {
+            assert!(load_first(&EmbeddedTessdata, model).is_ok(), "{model}");
+        }
+    }
+
+    #[test]
+    fn missing_models_are_all_named() {
+        let source = MemoryTessdata::new()
+            .with_model("eng", &b"eng"[..])
+            .with_model("osd", &b"osd"[..]);
+        let models = ["eng", "deu", "osd", "fra"].map(String::from);
+        match load_all(&source, &models) {
+            Err(OcrError::MissingTrainedData { languages }) => assert_eq!(languages, ["deu", "fra"]),
+            other => panic!("expected MissingTrainedData, got {other:?}"),
+        }
+        let loaded = load_all(&source, &models[..1]).unwrap();
+        assert_eq!(loaded, [("eng".to_string(), Cow::Borrowed(&b"eng"[..]))]);
+    }
+
+    #[test]
+    fn adds_sublanguages_to_the_config() {
+        let model = traineddata(&[None, Some(b"unicharset"), None, Some(b"lstm")]);
+        let patched = with_sublanguages(&model, &["deu", "fra"]).unwrap();
+        assert_eq!(patched, traineddata(&[
+            Some(b"tessedit_load_sublangs deu+fra\n"),
+            Some(b"unicharset"),
+            None,
+            Some(b"lstm"),
+        ]));
+        assert_eq!(with_sublanguages(&model, &[]).unwrap(), model);
+    }
+
+    #[test]
+    fn extends_an_existing_config() {
+        let model = traineddata(&[Some(b"debug_file /dev/null"), Some(b"unicharset")]);
+        let patched = with_sublanguages(&model, &["script/Latin"]).unwrap();
+        assert_eq!(patched, traineddata(&[
+            Some(b"debug_file /dev/null\ntessedit_load_sublangs script/Latin\n"),
+            Some(b"unicharset"),
+        ]));
+    }
+
+    #[test]
+    fn rejects_invalid_models() {
+        for model in [&b""[..], b"\x01\0\0", b"\0\0\0\0", b"\xff\xff\xff\xff"] {
+            assert!(matches!(with_sublanguages(model, &["deu"]), Err(OcrError::InvalidTrainedData(_))));
+        }
+        let truncated = traineddata(&[None, Some(b"unicharset")]);
+        assert!(with_sublanguages(&truncated[..10], &["deu"]).is_err());
+        let mut out_of_range = truncated.clone();
+        out_of_range[12..20].copy_from_slice(&1000i64.to_le_bytes());
+        assert!(with_sublanguages(&out_of_range, &["deu"]).is_err());
+    }
+
+    #[test]
+    fn bundled_model() {
+        let model = EmbeddedTessdata.load("eng").unwrap();
+        if let Some(model) = model {
+            let patched = with_sublanguages(&model, &["deu"]).unwrap();
+            assert_eq!(patched.len(), model.len() + "tessedit_load_sublangs deu\n".len());
+            assert_eq!(&patched[patched.len() - 1000..], &model[model.len() - 1000..]);
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! Emscripten time and locale imports
+//!
+//! The JS glue asks `Date` and `performance` for the current time and the
+//! local time zone. Here every instance reads the time from a `Clock`:
+//! `SystemClock` by default, `FixedClock` to get byte-identical output
+//! (hOCR / PDF timestamps, timing logs) across runs.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use wasmer::{FunctionEnvMut, MemorySize, MemoryView, RuntimeError, WasmPtr};
+use crate::env::{EmscriptenEnv, read_c_string, read_u32, trap, write_u32};
+use crate::invoke;
+
+/// Source of wall clock time, monotonic time and time zone for the guest
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Milliseconds since the Unix epoch, `Date.now()`
+    fn now(&self) -> f64;
+
+    /// Milliseconds since an arbitrary point in time, `performance.now()`
+    fn monotonic_now(&self) -> f64;
+
+    /// Offset of local time to UTC in seconds at `time` (seconds since the
+    /// epoch), positive east of Greenwich
+    fn utc_offset(&self, _time: i64) -> i32 {
+        0
+    }
+
+    /// Name of the local time zone, used for `tzname` and `%Z`
+    fn zone_name(&self) -> String {
+        "UTC".to_string()
+    }
+}
+
+/// The host's clock, local time is reported as UTC
+#[derive(Debug, Clone)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64() * 1000.0)
+            .unwrap_or(0.0)
+    }
+
+    fn monotonic_now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64() * 1000.0
+    }
+}
+
+/// A clock that is stopped at a given time, for reproducible output
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixedClock {
+    /// milliseconds since the Unix epoch
+    pub time: f64,
+    /// seconds east of UTC
+    pub utc_offset: i32,
+    pub zone_name: String,
+}
+
+impl FixedClock {
+    /// A clock stopped at `time` milliseconds since the Unix epoch, in UTC
+    pub fn new(time: f64) -> Self {
+        Self { time, utc_offset: 0, zone_name: "UTC".to_string() }
+    }
+
+    pub fn with_time_zone(mut self, utc_offset: i32, zone_name: &str) -> Self {
+        self.utc_offset = utc_offset;
+        self.zone_name = zone_name.to_string();
+        self
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> f64 {
+        self.time
+    }
+
+    fn monotonic_now(&self) -> f64 {
+        0.0
+    }
+
+    fn utc_offset(&self, _time: i64) -> i32 {
+        self.utc_offset
+    }
+
+    fn zone_name(&self) -> String {
+        self.zone_name.clone()
+    }
+}
+
+/// Time related state of one instance
+#[derive(Debug, Clone)]
+pub struct TimeEnv {
+    pub clock: Arc<dyn Clock>,
+    /// `__tzset_js.called`
+    pub tzset_called: bool,
+}
+
+impl TimeEnv {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self { clock, tzset_called: false }
+    }
+}
+
+impl Default for TimeEnv {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock::new()))
+    }
+}
+
+/// `struct tm` as laid out by emscripten's musl
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Tm {
+    sec: i32,
+    min: i32,
+    hour: i32,
+    mday: i32,
+    mon: i32,
+    /// years since 1900
+    year: i32,
+    wday: i32,
+    yday: i32,
+    isdst: i32,
+    gmtoff: i32,
+    /// `const char* tm_zone`
+    zone: u32,
+}
+
+impl Tm {
+    fn read(view: &MemoryView, ptr: u64) -> Result<Self, String> {
+        let field = |i: u64| read_u32(view, ptr + i * 4).map_err(|e| e.to_string());
+        Ok(Self {
+            sec: field(0)? as i32,
+            min: field(1)? as i32,
+            hour: field(2)? as i32,
+            mday: field(3)? as i32,
+            mon: field(4)? as i32,
+            year: field(5)? as i32,
+            wday: field(6)? as i32,
+            yday: field(7)? as i32,
+            isdst: field(8)? as i32,
+            gmtoff: field(9)? as i32,
+            zone: field(10)?,
+        })
+    }
+
+    /// Writes everything except `tm_zone`, like the JS glue
+    fn write(&self, view: &MemoryView, ptr: u64) -> Result<(), String> {
+        let fields = [
+            self.sec, self.min, self.hour, self.mday, self.mon, self.year,
+            self.wday, self.yday, self.isdst, self.gmtoff,
+        ];
+        for (i, v) in fields.iter().enumerate() {
+            write_u32(view, ptr + i as u64 * 4, *v as u32).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Breaks down `time` (seconds since the epoch) shifted by `offset` seconds
+    fn from_time(time: i64, offset: i32) -> Self {
+        let local = time + offset as i64;
+        let days = local.div_euclid(86400);
+        let secs = local.rem_euclid(86400);
+        let (year, mon, mday) = civil_from_days(days);
+        Self {
+            sec: (secs % 60) as i32,
+            min: (secs / 60 % 60) as i32,
+            hour: (secs / 3600) as i32,
+            mday,
+            mon,
+            year: (year - 1900) as i32,
+            wday: (days + 4).rem_euclid(7) as i32,
+            yday: (days - days_from_civil(year, 0, 1)) as i32,
+            isdst: 0,
+            gmtoff: offset,
+            zone: 0,
+        }
+    }
+
+    /// Seconds since the epoch of the (possibly denormalized) local fields
+    fn to_local_time(&self) -> i64 {
+        let year = self.year as i64 + 1900 + (self.mon as i64).div_euclid(12);
+        let mon = (self.mon as i64).rem_euclid(12);
+        let days = days_from_civil(year, mon, 1) + self.mday as i64 - 1;
+        days * 86400 + self.hour as i64 * 3600 + self.min as i64 * 60 + self.sec as i64
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, `mon` is 0-based
+fn days_from_civil(year: i64, mon: i64, mday: i64) -> i64 {
+    let year = if mon < 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (mon + 10) % 12;
+    let doy = (153 * mp + 2) / 5 + mday - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Inverse of `days_from_civil`, returns (year, 0-based month, day of month)
+fn civil_from_days(days: i64) -> (i64, i32, i32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let mday = doy - (153 * mp + 2) / 5 + 1;
+    let mon = (mp + 2) % 12;
+    let year = yoe + era * 400 + if mon < 2 { 1 } else { 0 };
+    (year, mon as i32, mday as i32)
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// ISO 8601 week number and week-based year (`%V`, `%G`)
+fn iso_week(tm: &Tm) -> (i32, i64) {
+    let year = tm.year as i64 + 1900;
+    let weekday = (tm.wday + 6) % 7 + 1;
+    let week = (tm.yday + 1 - weekday + 10) / 7;
+    let weeks_in = |year: i64, jan1_wday: i32| {
+        if jan1_wday == 4 || (jan1_wday == 3 && is_leap_year(year)) { 53 } else { 52 }
+    };
+    let jan1_wday = (tm.wday - tm.yday).rem_euclid(7);
+    if week < 1 {
+        let prev_len = if is_leap_year(year - 1) { 366 } else { 365 };
+        let prev_jan1 = (jan1_wday - prev_len).rem_euclid(7);
+        (weeks_in(year - 1, prev_jan1), year - 1)
+    } else if week > weeks_in(year, jan1_wday) {
+        (1, year + 1)
+    } else {
+        (week, year)
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+const MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December",
+];
+
+/// `_strftime` of the JS glue: the C locale, `E` and `O` modifiers are ignored
+fn strftime(format: &str, tm: &Tm, zone: &str) -> String {
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut conversion = chars.next();
+        if let Some('E' | 'O') = conversion {
+            conversion = chars.next();
+        }
+        let year = tm.year as i64 + 1900;
+        let weekday = WEEKDAYS.get(tm.wday as usize).copied().unwrap_or("?");
+        let month = MONTHS.get(tm.mon as usize).copied().unwrap_or("?");
+        match conversion {
+            Some('a') => out.push_str(weekday.get(..3).unwrap_or(weekday)),
+            Some('A') => out.push_str(weekday),
+            Some('b' | 'h') => out.push_str(month.get(..3).unwrap_or(month)),
+            Some('B') => out.push_str(month),
+            Some('c') => out.push_str(&strftime("%a %b %d %H:%M:%S %Y", tm, zone)),
+            Some('C') => out.push_str(&format!("{:02}", year / 100)),
+            Some('d') => out.push_str(&format!("{:02}", tm.mday)),
+            Some('D' | 'x') => out.push_str(&strftime("%m/%d/%y", tm, zone)),
+            Some('e') => out.push_str(&format!("{:2}", tm.mday)),
+            Some('F') => out.push_str(&strftime("%Y-%m-%d", tm, zone)),
+            Some('g') => out.push_str(&format!("{:02}", iso_week(tm).1 % 100)),
+            Some('G') => out.push_str(&iso_week(tm).1.to_string()),
+            Some('H') => out.push_str(&format!("{:02}", tm.hour)),
+            Some('I') => out.push_str(&format!("{:02}", match tm.hour { 0 => 12, h if h > 12 => h - 12, h => h })),
+            Some('j') => out.push_str(&format!("{:03}", tm.yday + 1)),
+            Some('m') => out.push_str(&format!("{:02}", tm.mon + 1)),
+            Some('M') => out.push_str(&format!("{:02}", tm.min)),
+            Some('n') => out.push('\n'),
+            Some('p') => out.push_str(if (0..12).contains(&tm.hour) { "AM" } else { "PM" }),
+            Some('r') => out.push_str(&strftime("%I:%M:%S %p", tm, zone)),
+            Some('R') => out.push_str(&strftime("%H:%M", tm, zone)),
+            Some('S') => out.push_str(&format!("{:02}", tm.sec)),
+            Some('t') => out.push('\t'),
+            Some('T' | 'X') => out.push_str(&strftime("%H:%M:%S", tm, zone)),
+            Some('u') => out.push_str(&(if tm.wday == 0 { 7 } else { tm.wday }).to_string()),
+            Some('U') => out.push_str(&format!("{:02}", (tm.yday + 7 - tm.wday) / 7)),
+            Some('V') => out.push_str(&format!("{:02}", iso_week(tm).0)),
+            Some('w') => out.push_str(&tm.wday.to_string()),
+            Some('W') => out.push_str(&format!("{:02}", (tm.yday + 7 - (tm.wday + 6) % 7) / 7)),
+            Some('y') => out.push_str(&format!("{:02}", year.rem_euclid(100))),
+            Some('Y') => out.push_str(&year.to_string()),
+            Some('z') => {
+                let off = tm.gmtoff.abs() / 60;
+                let sign = if tm.gmtoff >= 0 { '+' } else { '-' };
+                out.push_str(&format!("{sign}{:02}{:02}", off / 60, off % 60));
+            },
+            Some('Z') => out.push_str(zone),
+            Some('%') => out.push('%'),
+            // unknown conversions are copied verbatim
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            },
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+fn read_time(view: &MemoryView, ptr: u64) -> Result<i64, RuntimeError> {
+    // time_t is 32 bit in this build
+    Ok(read_u32(view, ptr).map_err(trap)? as i32 as i64)
+}
+
+// ----------
+//
+// __emscripten_date_now() -> f64
+pub fn __emscripten_date_now<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+) -> f64 {
+    ctx.data().time.clock.now()
+}
+
+// ----------
+//
+// _emscripten_get_now() -> f64
+pub fn _emscripten_get_now<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+) -> f64 {
+    ctx.data().time.clock.monotonic_now()
+}
+
+// ----------
+//
+// __emscripten_get_now_is_monotonic() -> bool
+pub fn __emscripten_get_now_is_monotonic<M: MemorySize>(
+    _ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+) -> u32 {
+    1
+}
+
+// ----------
+//
+// __gmtime_js(time, tmPtr)
+pub fn __gmtime_js<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    time: WasmPtr<u8, M>,
+    tm: WasmPtr<u8, M>,
+) -> Result<(), RuntimeError> {
+    let view = ctx.data().memory_view(&ctx);
+    let time = read_time(&view, time.offset().into())?;
+    Tm::from_time(time, 0).write(&view, tm.offset().into()).map_err(trap)
+}
+
+// ----------
+//
+// __localtime_js(time, tmPtr)
+pub fn __localtime_js<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    time: WasmPtr<u8, M>,
+    tm: WasmPtr<u8, M>,
+) -> Result<(), RuntimeError> {
+    let view = ctx.data().memory_view(&ctx);
+    let time = read_time(&view, time.offset().into())?;
+    let offset = ctx.data().time.clock.utc_offset(time);
+    Tm::from_time(time, offset).write(&view, tm.offset().into()).map_err(trap)
+}
+
+// ----------
+//
+// __mktime_js(tmPtr) -> time_t
+pub fn __mktime_js<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    tm: WasmPtr<u8, M>,
+) -> Result<i32, RuntimeError> {
+    let view = ctx.data().memory_view(&ctx);
+    let local = Tm::read(&view, tm.offset().into()).map_err(trap)?.to_local_time();
+    let clock = &ctx.data().time.clock;
+    let time = local - clock.utc_offset(local) as i64;
+    // normalizes the fields and fills in tm_wday / tm_yday
+    Tm::from_time(time, clock.utc_offset(time)).write(&view, tm.offset().into()).map_err(trap)?;
+    Ok(time as i32)
+}
+
+// ----------
+//
+// __tzset_js(timezone, daylight, tzname)
+pub fn __tzset_js<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    timezone: WasmPtr<u8, M>,
+    daylight: WasmPtr<u8, M>,
+    tzname: WasmPtr<u8, M>,
+) -> Result<(), RuntimeError> {
+    if ctx.data().time.tzset_called {
+        return Ok(());
+    }
+    ctx.data_mut().time.tzset_called = true;
+
+    let clock = ctx.data().time.clock.clone();
+    let offset = clock.utc_offset((clock.now() / 1000.0) as i64);
+    // allocateUTF8, the names live as long as the instance
+    let mut name = clock.zone_name().into_bytes();
+    name.push(0);
+    let env = ctx.as_ref();
+    let name_ptr = invoke::malloc(&mut ctx, &env, name.len() as u32).map_err(trap)?;
+
+    let view = ctx.data().memory_view(&ctx);
+    view.write(name_ptr as u64, &name).map_err(trap)?;
+    // seconds *west* of UTC, no daylight saving time
+    write_u32(&view, timezone.offset().into(), (-offset) as u32).map_err(trap)?;
+    write_u32(&view, daylight.offset().into(), 0).map_err(trap)?;
+    let tzname: u64 = tzname.offset().into();
+    write_u32(&view, tzname, name_ptr).map_err(trap)?;
+    write_u32(&view, tzname + 4, name_ptr).map_err(trap)?;
+    Ok(())
+}
+
+// ----------
+//
+// _strftime(s, maxsize, format, tm) -> size_t
+pub fn _strftime<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    s: WasmPtr<u8, M>,
+    max_size: u32,
+    format: WasmPtr<u8, M>,
+    tm: WasmPtr<u8, M>,
+) -> Result<u32, RuntimeError> {
+    let view = ctx.data().memory_view(&ctx);
+    let format = read_c_string(&view, format.offset().into()).map_err(trap)?;
+    let tm = Tm::read(&view, tm.offset().into()).map_err(trap)?;
+    let zone = match tm.zone {
+        0 => Vec::new(),
+        ptr => read_c_string(&view, ptr as u64).map_err(trap)?,
+    };
+    let mut bytes = strftime(&String::from_utf8_lossy(&format), &tm, &String::from_utf8_lossy(&zone)).into_bytes();
+    bytes.push(0);
+    if bytes.len() > max_size as usize {
+        return Ok(0);
+    }
+    view.write(s.offset().into(), &bytes).map_err(trap)?;
+    Ok(bytes.len() as u32 - 1)
+}
+
+// ----------
+//
+// _strftime_l(s, maxsize, format, tm, locale) -> size_t
+pub fn _strftime_l<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    s: WasmPtr<u8, M>,
+    max_size: u32,
+    format: WasmPtr<u8, M>,
+    tm: WasmPtr<u8, M>,
+    _locale: u32,
+) -> Result<u32, RuntimeError> {
+    _strftime::<M>(ctx, s, max_size, format, tm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i64, mon: i64, mday: i64) -> Tm {
+        Tm::from_time(days_from_civil(year, mon, mday) * 86400, 0)
+    }
+
+    #[test]
+    fn civil_conversions() {
+        assert_eq!(days_from_civil(1970, 0, 1), 0);
+        assert_eq!(days_from_civil(1969, 11, 31), -1);
+        assert_eq!(days_from_civil(2000, 1, 29), 11016);
+        assert_eq!(days_from_civil(0, 2, 1), -719468);
+        // year 0 is a leap year, 1900 is not
+        assert_eq!(civil_from_days(-719469), (0, 1, 29));
+        assert_eq!(civil_from_days(days_from_civil(1900, 2, 1) - 1), (1900, 1, 28));
+        for days in (-800 * 366..800 * 366).step_by(7) {
+            let (year, mon, mday) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, mon as i64, mday as i64), days);
+        }
+    }
+
+    #[test]
+    fn from_time_before_epoch() {
+        let tm = Tm::from_time(-1, 0);
+        assert_eq!((tm.year, tm.mon, tm.mday, tm.hour, tm.min, tm.sec), (69, 11, 31, 23, 59, 59));
+        assert_eq!((tm.wday, tm.yday), (3, 364));
+    }
+
+    #[test]
+    fn strftime_year_boundaries() {
+        let format = "%G %V %U %W %j";
+        // Friday, in the last ISO week of 2020
+        assert_eq!(strftime(format, &date(2021, 0, 1), "UTC"), "2020 53 00 00 001");
+        // Sunday, in the last ISO week of 2022
+        assert_eq!(strftime(format, &date(2023, 0, 1), "UTC"), "2022 52 01 00 001");
+        // Thursday of a leap year
+        assert_eq!(strftime(format, &date(2020, 11, 31), "UTC"), "2020 53 52 52 366");
+        // Monday, already in the first ISO week of 2025
+        assert_eq!(strftime(format, &date(2024, 11, 30), "UTC"), "2025 01 52 53 365");
+        assert_eq!(strftime("%g", &date(2024, 11, 30), "UTC"), "25");
+    }
+
+    #[test]
+    fn strftime_composites() {
+        let mut tm = Tm::from_time(0, -19800);
+        assert_eq!(strftime("%c", &tm, "IST"), "Wed Dec 31 18:30:00 1969");
+        assert_eq!(strftime("%D %r %z %Z %%", &tm, "IST"), "12/31/69 06:30:00 PM -0530 IST %");
+        tm.hour = 0;
+        assert_eq!(strftime("%I %p %u %Ex %q", &tm, "IST"), "12 AM 3 12/31/69 %q");
+    }
+
+    #[test]
+    fn mktime_normalizes() {
+        let tm = Tm { year: 120, mon: 12, mday: 1, ..Tm::default() };
+        assert_eq!(tm.to_local_time(), 1609459200);
+        // day 0 of March is the leap day
+        let tm = Tm { year: 124, mon: 2, mday: 0, hour: 25, ..Tm::default() };
+        assert_eq!(tm.to_local_time(), days_from_civil(2024, 2, 1) * 86400 + 3600);
+        let tm = Tm { year: 70, mon: -1, mday: 1, ..Tm::default() };
+        assert_eq!(Tm::from_time(tm.to_local_time(), 0), date(1969, 11, 1));
+    }
+}
@@ -0,0 +1,213 @@
+//! Aborts and traps of the guest
+//!
+//! A trap (`unreachable`, `abort()`, a failing host import) unwinds the
+//! whole call into the guest. The first trap of a call is recorded as a
+//! `GuestTrap` together with the context that explains it: the wasm
+//! backtrace, the host import that was running and the last output of the
+//! guest, which is where tesseract prints why it gave up.
+
+use std::fmt;
+use wasmer::{AsStoreMut, Function, FunctionEnv, FunctionEnvMut, MemorySize, RuntimeError, Value};
+use crate::env::{EmscriptenEnv, trap};
+use crate::error::OcrError;
+
+/// A trap of the guest with the context at the time it happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestTrap {
+    /// trap message, `Aborted()` for calls to `abort`
+    pub message: String,
+    /// the host import that was executing, if the trap was raised by one
+    pub import: Option<String>,
+    /// wasm frames, innermost first, named from the name section if present
+    pub backtrace: Vec<String>,
+    /// the last `OUTPUT_TAIL_SIZE` bytes written to stdout / stderr
+    pub output: String,
+}
+
+impl fmt::Display for GuestTrap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "guest trapped: {}", self.message)?;
+        if let Some(import) = &self.import {
+            write!(f, " (in host import {import})")?;
+        }
+        for (i, frame) in self.backtrace.iter().enumerate() {
+            write!(f, "\n    #{i} {frame}")?;
+        }
+        if !self.output.is_empty() {
+            write!(f, "\n  output:")?;
+            for line in self.output.lines() {
+                write!(f, "\n    {line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Trap state of one instance
+#[derive(Debug, Clone, Default)]
+pub struct TrapEnv {
+    /// symbols of the host imports currently executing, innermost last
+    pub imports: Vec<&'static str>,
+    /// first trap of the current call
+    pub last: Option<GuestTrap>,
+}
+
+/// Records `e` as the trap of the current call unless one is recorded already
+pub fn record(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>, e: &RuntimeError) {
+    let env = env.as_mut(store);
+    let import = env.trap.imports.last().map(|s| s.to_string());
+    env.trap.imports.clear();
+    if env.trap.last.is_some() {
+        return;
+    }
+    let backtrace = e.trace().iter()
+        .map(|frame| {
+            let name = frame.function_name()
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| format!("func[{}]", frame.func_index()));
+            format!("{}!{name} @ {:#x}", frame.module_name(), frame.module_offset())
+        })
+        .collect();
+    env.trap.last = Some(GuestTrap {
+        message: e.message(),
+        import,
+        backtrace,
+        output: env.fs.output_tail(),
+    });
+}
+
+/// Takes the recorded trap as an `OcrError::Trap`
+pub fn take(store: &mut impl AsStoreMut, env: &FunctionEnv<EmscriptenEnv>) -> Option<OcrError> {
+    env.as_mut(store).trap.last.take().map(OcrError::Trap)
+}
+
+/// Calls `f` and records the trap if it fails, for every call into the guest
+///
+/// A trap or a C++ throw leaves the shadow stack pointer wherever the
+/// unwound frames had moved it. The build exports no `stackRestore` to put
+/// it back, so the instance is done for after any failed call.
+pub fn call(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    f: &Function,
+    args: &[Value],
+) -> Result<Box<[Value]>, RuntimeError> {
+    env.as_mut(store).interrupt.check()
+        .and_then(|_| f.call(store, args))
+        .map_err(|e| {
+            record(store, env, &e);
+            e
+        })
+}
+
+/// Wraps a host import so that it shows up as `GuestTrap::import` when
+/// it traps, and so that it interrupts the guest once the call's deadline
+/// passed or it was cancelled
+pub fn traced(
+    store: &mut impl AsStoreMut,
+    env: &FunctionEnv<EmscriptenEnv>,
+    symbol: &'static str,
+    function: Function,
+) -> Function {
+    let ty = function.ty(&*store);
+    Function::new_with_env(store, env, ty, move |mut ctx: FunctionEnvMut<'_, EmscriptenEnv>, args: &[Value]| {
+        ctx.data_mut().interrupt.check()?;
+        ctx.data_mut().trap.imports.push(symbol);
+        // left on the stack if the import traps, `record` picks it up
+        let result = function.call(&mut ctx, args)?;
+        ctx.data_mut().trap.imports.pop();
+        Ok(result.into_vec())
+    })
+}
+
+// ----------
+//
+// _abort()
+pub fn _abort<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+) -> Result<(), RuntimeError> {
+    // `abort("")`: err("Aborted()") and throw
+    let message = "Aborted()";
+    let _ = ctx.data_mut().fs.write(2, format!("{message}\n").as_bytes());
+    Err(trap(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{Instance, Memory32, Module, Store, imports};
+    use super::*;
+
+    /// `run` calls `abort` from a named function, `fail` traps in the guest
+    const TRAPPING_GUEST: &str = r#"
+        (module
+            (import "a" "b" (func $abort))
+            (func $inner (call $abort))
+            (func (export "run") (call $inner))
+            (func (export "fail") unreachable))
+    "#;
+
+    fn trapping_guest() -> (Store, FunctionEnv<EmscriptenEnv>, Instance) {
+        let mut store = Store::default();
+        let module = Module::new(&store, TRAPPING_GUEST).unwrap();
+        let env = FunctionEnv::new(&mut store, EmscriptenEnv::new());
+        let abort = Function::new_typed_with_env(&mut store, &env, _abort::<Memory32>);
+        let abort = traced(&mut store, &env, "_abort", abort);
+        let instance = Instance::new(&mut store, &module, &imports! { "a" => { "b" => abort } }).unwrap();
+        (store, env, instance)
+    }
+
+    fn run(store: &mut Store, env: &FunctionEnv<EmscriptenEnv>, instance: &Instance, name: &str) -> GuestTrap {
+        let f = instance.exports.get_function(name).unwrap().clone();
+        assert!(call(store, env, &f, &[]).is_err());
+        match take(store, env) {
+            Some(OcrError::Trap(trap)) => trap,
+            other => panic!("expected a trap, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn records_aborts() {
+        let (mut store, env, instance) = trapping_guest();
+        env.as_mut(&mut store).fs.write(1, b"Error opening data file\n").unwrap();
+        let trap = run(&mut store, &env, &instance, "run");
+        assert_eq!(trap.message, "Aborted()");
+        assert_eq!(trap.import.as_deref(), Some("_abort"));
+        assert_eq!(trap.output, "Error opening data file\nAborted()\n");
+        assert!(trap.backtrace.len() >= 2, "{:?}", trap.backtrace);
+        assert!(trap.backtrace[0].contains("!inner @ 0x"), "{:?}", trap.backtrace);
+        assert!(env.as_ref(&store).trap.imports.is_empty());
+        assert!(take(&mut store, &env).is_none());
+    }
+
+    #[test]
+    fn records_the_first_trap() {
+        let (mut store, env, instance) = trapping_guest();
+        let trap = run(&mut store, &env, &instance, "fail");
+        assert!(trap.message.contains("unreachable"), "{}", trap.message);
+        assert_eq!(trap.import, None);
+
+        // a second failure before `take` keeps the first one
+        let fail = instance.exports.get_function("fail").unwrap().clone();
+        let run_f = instance.exports.get_function("run").unwrap().clone();
+        assert!(call(&mut store, &env, &fail, &[]).is_err());
+        assert!(call(&mut store, &env, &run_f, &[]).is_err());
+        match take(&mut store, &env) {
+            Some(OcrError::Trap(trap)) => assert_eq!(trap.import, None),
+            other => panic!("expected a trap, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn displays_the_context() {
+        let trap = GuestTrap {
+            message: "Aborted()".to_string(),
+            import: Some("_abort".to_string()),
+            backtrace: vec!["tesseract!abort @ 0x10".to_string(), "tesseract!main @ 0x20".to_string()],
+            output: "first\nsecond\n".to_string(),
+        };
+        assert_eq!(trap.to_string(), "guest trapped: Aborted() (in host import _abort)\n    \
+            #0 tesseract!abort @ 0x10\n    #1 tesseract!main @ 0x20\n  output:\n    first\n    second");
+        let bare = GuestTrap { import: None, backtrace: Vec::new(), output: String::new(), ..trap };
+        assert_eq!(bare.to_string(), "guest trapped: Aborted()");
+    }
+}
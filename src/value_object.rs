@@ -0,0 +1,175 @@
+//! Host side of embind `value_object<T>` bindings
+//!
+//! Value objects (`TextRect`, `TextItem`, `BoxItem`, ...) are plain structs
+//! copied across the boundary field by field. Registration happens in three
+//! steps: the struct, each field with its getter / setter, and a final
+//! `__embind_finalize_value_object` which turns the collected fields into a
+//! `TypeKind::ValueObject` in the type registry.
+
+use wasmer::{FunctionEnvMut, MemorySize, RuntimeError, WasmPtr};
+use crate::class::{FunctionPtr, read_function_ptr};
+use crate::embind::{RawType, RegisteredType, TypeKind};
+use crate::env::{EmscriptenEnv, read_latin1_string, trap};
+
+/// One `.field("name", &T::name)` of a value object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueObjectField {
+    pub name: String,
+    pub getter_return_type: RawType,
+    pub getter: FunctionPtr,
+    pub getter_context: u32,
+    pub setter_argument_type: RawType,
+    pub setter: FunctionPtr,
+    pub setter_context: u32,
+}
+
+/// A value object between `__embind_register_value_object` and
+/// `__embind_finalize_value_object` (`structRegistrations` in the JS glue)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructRegistration {
+    pub name: String,
+    pub raw_constructor: FunctionPtr,
+    pub raw_destructor: FunctionPtr,
+    pub fields: Vec<ValueObjectField>,
+}
+
+// ----------
+//
+// __embind_register_value_object(rawType, name, constructorSignature,
+//     rawConstructor, destructorSignature, rawDestructor)
+pub fn __embind_register_value_object<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    raw_type: u32,
+    name: WasmPtr<u8, M>,
+    constructor_signature: WasmPtr<u8, M>,
+    raw_constructor: u32,
+    destructor_signature: WasmPtr<u8, M>,
+    raw_destructor: u32,
+) -> Result<(), RuntimeError> {
+    let name = {
+        let view = ctx.data().memory_view(&ctx);
+        read_latin1_string(&view, name.offset().into()).map_err(trap)?
+    };
+    let registration = StructRegistration {
+        name,
+        raw_constructor: read_function_ptr(&ctx, constructor_signature, raw_constructor)?,
+        raw_destructor: read_function_ptr(&ctx, destructor_signature, raw_destructor)?,
+        fields: Vec::new(),
+    };
+    ctx.data_mut().struct_registrations.insert(raw_type, registration);
+    Ok(())
+}
+
+// ----------
+//
+// __embind_register_value_object_field(structType, fieldName, getterReturnType,
+//     getterSignature, getter, getterContext, setterArgumentType,
+//     setterSignature, setter, setterContext)
+#[allow(clippy::too_many_arguments)]
+pub fn __embind_register_value_object_field<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    struct_type: u32,
+    field_name: WasmPtr<u8, M>,
+    getter_return_type: u32,
+    getter_signature: WasmPtr<u8, M>,
+    getter: u32,
+    getter_context: u32,
+    setter_argument_type: u32,
+    setter_signature: WasmPtr<u8, M>,
+    setter: u32,
+    setter_context: u32,
+) -> Result<(), RuntimeError> {
+    let name = {
+        let view = ctx.data().memory_view(&ctx);
+        read_latin1_string(&view, field_name.offset().into()).map_err(trap)?
+    };
+    let field = ValueObjectField {
+        name,
+        getter_return_type,
+        getter: read_function_ptr(&ctx, getter_signature, getter)?,
+        getter_context,
+        setter_argument_type,
+        setter: read_function_ptr(&ctx, setter_signature, setter)?,
+        setter_context,
+    };
+    ctx.data_mut().struct_registrations
+        .get_mut(&struct_type)
+        .ok_or_else(|| trap(format!("field {}: value object {struct_type:#x} is not registered", field.name)))?
+        .fields
+        .push(field);
+    Ok(())
+}
+
+// ----------
+//
+// __embind_finalize_value_object(structType)
+pub fn __embind_finalize_value_object<M: MemorySize>(
+    mut ctx: FunctionEnvMut<'_, EmscriptenEnv>,
+    struct_type: u32,
+) -> Result<(), RuntimeError> {
+    let env = ctx.data_mut();
+    let reg = env.struct_registrations
+        .remove(&struct_type)
+        .ok_or_else(|| trap(format!("value object {struct_type:#x} is not registered")))?;
+    env.registry.register(struct_type, RegisteredType {
+        name: reg.name,
+        kind: TypeKind::ValueObject {
+            constructor: reg.raw_constructor,
+            destructor: reg.raw_destructor,
+            fields: reg.fields,
+        },
+    }).map_err(trap)
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{Function, Memory32};
+    use crate::env::testing::{call, env_with_memory, write_c_string};
+    use super::*;
+
+    const TEXT_RECT: i32 = 200;
+    const INT: u32 = 8;
+
+    #[test]
+    fn registers_value_objects() {
+        let (mut store, env) = env_with_memory();
+        for (offset, s) in [(64, "TextRect"), (96, "i"), (104, "vi"), (112, "left"), (120, "top"), (128, "iii"), (136, "viii")] {
+            write_c_string(&store, &env, offset, s);
+        }
+        let register = Function::new_typed_with_env(&mut store, &env, __embind_register_value_object::<Memory32>);
+        let register_field = Function::new_typed_with_env(&mut store, &env, __embind_register_value_object_field::<Memory32>);
+        let finalize = Function::new_typed_with_env(&mut store, &env, __embind_finalize_value_object::<Memory32>);
+        let field = |name: i32, getter: i32| [TEXT_RECT, name, INT as i32, 128, getter, 0, INT as i32, 136, getter + 1, 0];
+
+        let e = call(&mut store, &register_field, &field(112, 3)).unwrap_err();
+        assert!(e.contains("field left: value object 0xc8 is not registered"), "{e}");
+        call(&mut store, &register, &[TEXT_RECT, 64, 96, 1, 104, 2]).unwrap();
+        call(&mut store, &register_field, &field(112, 3)).unwrap();
+        call(&mut store, &register_field, &field(120, 5)).unwrap();
+        // fields are collected until the object is finalized
+        assert!(env.as_ref(&store).registry.get(TEXT_RECT as u32).is_none());
+        call(&mut store, &finalize, &[TEXT_RECT]).unwrap();
+        assert!(call(&mut store, &finalize, &[TEXT_RECT]).is_err());
+
+        let env = env.as_ref(&store);
+        assert!(env.struct_registrations.is_empty());
+        let ty = env.registry.get(TEXT_RECT as u32).unwrap();
+        let (constructor, destructor, fields) = match &ty.kind {
+            TypeKind::ValueObject { constructor, destructor, fields } => (constructor, destructor, fields),
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(ty.name, "TextRect");
+        assert_eq!(constructor, &FunctionPtr { signature: "i".to_string(), index: 1 });
+        assert_eq!(destructor, &FunctionPtr { signature: "vi".to_string(), index: 2 });
+        assert_eq!(fields.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), ["left", "top"]);
+        assert_eq!(fields[1], ValueObjectField {
+            name: "top".to_string(),
+            getter_return_type: INT,
+            getter: FunctionPtr { signature: "iii".to_string(), index: 5 },
+            getter_context: 0,
+            setter_argument_type: INT,
+            setter: FunctionPtr { signature: "viii".to_string(), index: 6 },
+            setter_context: 0,
+        });
+    }
+}